@@ -0,0 +1,169 @@
+use std::fs;
+use std::time::Duration;
+use elasticsearch::auth::{ClientCertificate, Credentials};
+use elasticsearch::cert::{Certificate, CertificateValidation};
+use elasticsearch::http::transport::{SingleNodeConnectionPool, Transport, TransportBuilder};
+use elasticsearch::http::Url;
+use reqwest::ClientBuilder;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use crate::config::Config;
+
+
+/// How to log in to Elasticsearch, and which certificates to trust.
+/// Used for both the Elasticsearch client and the plain requests, so they always connect the same way
+#[derive(Clone, Default)]
+pub struct Auth {
+    /// Username and password, from the config file or `user:pass@` in the url
+    basic: Option<(String, String)>,
+    /// Id and key of an API key
+    api_key: Option<(String, String)>,
+    bearer: Option<String>,
+    /// PEM encoded, can hold a whole chain of certificates
+    ca: Option<Vec<u8>>,
+    /// PKCS#12 archive with the client certificate and its key, and the password for it
+    identity: Option<(Vec<u8>, String)>,
+    /// Don't check the certificate of the server at all
+    insecure: bool
+}
+impl Auth {
+    /// Reads the certificates the config file points to, so a bad one is found before anything is sent
+    pub fn from_config(config : &Config) -> Result<Self, String> {
+        let mut auth = Auth {
+            insecure: config.insecure_skip_verify,
+            ..Auth::default()
+        };
+
+        if let Some(username) = &config.username {
+            auth.basic = Some((username.clone(), config.password.clone().unwrap_or_default()));
+        }
+        if let Some(api_key) = &config.api_key {
+            auth.api_key = Some(split_api_key(api_key.as_str())?);
+        }
+        auth.bearer = config.bearer_token.clone();
+        if let Some(path) = &config.ca_cert {
+            let ca = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+            Certificate::from_pem(&ca).map_err(|e| format!("Invalid certificate in {}: {}", path, e))?;
+            auth.ca = Some(ca);
+        }
+        if let Some(path) = &config.client_cert {
+            let identity = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+            let password = config.client_cert_password.clone().unwrap_or_default();
+            reqwest::Identity::from_pkcs12_der(&identity, password.as_str()).map_err(|e| format!("Invalid PKCS#12 file {}: {}", path, e))?;
+            auth.identity = Some((identity, password));
+        }
+
+        let methods = [auth.basic.is_some(), auth.api_key.is_some(), auth.bearer.is_some(), auth.identity.is_some()];
+        if methods.iter().filter(|set| **set).count() > 1 {
+            return Err(String::from("Only one of username, api_key, bearer_token and client_cert can be used"));
+        }
+        Ok(auth)
+    }
+
+    /// Credentials in the url of a server are used over the ones in the config file
+    pub fn with_basic(&self, basic : Option<(String, String)>) -> Self {
+        match basic {
+            Some(basic) => Auth {
+                basic: Some(basic),
+                api_key: None,
+                bearer: None,
+                identity: None,
+                ..self.clone()
+            },
+            None => self.clone()
+        }
+    }
+
+    /// If any way of logging in is set up
+    pub fn has_credentials(&self) -> bool {
+        self.credentials().is_some()
+    }
+
+    fn credentials(&self) -> Option<Credentials> {
+        if let Some((username, password)) = &self.basic {
+            return Some(Credentials::Basic(username.clone(), password.clone()));
+        }
+        if let Some((id, key)) = &self.api_key {
+            return Some(Credentials::ApiKey(id.clone(), key.clone()));
+        }
+        if let Some(token) = &self.bearer {
+            return Some(Credentials::Bearer(token.clone()));
+        }
+        if let Some((identity, password)) = &self.identity {
+            return Some(Credentials::Certificate(ClientCertificate::Pkcs12(identity.clone(), Some(password.clone()))));
+        }
+        None
+    }
+
+    /// The Elasticsearch client's connection to `host`, like `https://127.0.0.1:9200`
+    pub fn transport(&self, host : &str) -> Result<Transport, String> {
+        let url = Url::parse(host).map_err(|e| format!("Invalid url {}: {}", host, e))?;
+        let mut builder = TransportBuilder::new(SingleNodeConnectionPool::new(url));
+        if let Some(credentials) = self.credentials() {
+            builder = builder.auth(credentials);
+        }
+        if self.insecure {
+            builder = builder.cert_validation(CertificateValidation::None);
+        } else if let Some(ca) = &self.ca {
+            builder = builder.cert_validation(CertificateValidation::Full(Certificate::from_pem(ca).map_err(|e| e.to_string())?));
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+
+    /// A reqwest client set up like [Auth::transport], for the requests the Elasticsearch client doesn't cover
+    pub fn http(&self) -> ClientBuilder {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(16));
+
+        let authorization = if let Some((username, password)) = &self.basic {
+            Some(format!("Basic {}", base64::encode(format!("{}:{}", username, password))))
+        } else if let Some((id, key)) = &self.api_key {
+            Some(format!("ApiKey {}", base64::encode(format!("{}:{}", id, key))))
+        } else {
+            self.bearer.as_ref().map(|token| format!("Bearer {}", token))
+        };
+        if let Some(value) = authorization.and_then(|value| HeaderValue::from_str(value.as_str()).ok()) {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, value);
+            builder = builder.default_headers(headers);
+        }
+
+        if let Some((identity, password)) = &self.identity {
+            // Already checked when it was read
+            builder = builder.identity(reqwest::Identity::from_pkcs12_der(identity, password.as_str()).unwrap());
+        }
+        if self.insecure {
+            builder = builder.danger_accept_invalid_certs(true);
+        } else if let Some(ca) = &self.ca {
+            for pem in split_pem(ca) {
+                if let Ok(certificate) = reqwest::Certificate::from_pem(pem.as_bytes()) {
+                    builder = builder.add_root_certificate(certificate);
+                }
+            }
+        }
+        builder
+    }
+}
+
+/// Either `id:key`, or the base64 encoded form Kibana shows
+fn split_api_key(api_key : &str) -> Result<(String, String), String> {
+    let decoded = match api_key.contains(':') {
+        true => String::from(api_key),
+        false => base64::decode(api_key).ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(String::from("api_key has to be id:key, or the two base64 encoded"))?
+    };
+    match decoded.split_once(':') {
+        Some((id, key)) => Ok((String::from(id), String::from(key))),
+        None => Err(String::from("api_key has to be id:key, or the two base64 encoded"))
+    }
+}
+
+/// reqwest only reads the first certificate of a PEM file, so a bundle is split up
+fn split_pem(pem : &[u8]) -> Vec<String> {
+    const END : &str = "-----END CERTIFICATE-----";
+    String::from_utf8_lossy(pem)
+        .split_inclusive(END)
+        .filter(|part| part.contains(END))
+        .map(|part| String::from(part.trim()))
+        .collect()
+}
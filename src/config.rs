@@ -0,0 +1,71 @@
+use std::fs;
+use serde_derive::Deserialize;
+
+
+/// Settings read from the config file given with `-f` | `--config`
+/// Every field is optional, anything left out keeps its default
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// nginx `log_format`, either the bare format string or the whole directive from nginx.conf
+    pub log_format: Option<String>,
+    /// Keep keys from JSON logs that don't map to any field, instead of dropping them
+    pub json_passthrough: bool,
+    /// Proxies in CIDR notation whose X-Forwarded-For entries are believed, defaults to loopback only
+    pub trusted_proxies: Option<Vec<String>>,
+    /// GeoLite2 City database (.mmdb) to add `geo` to each document
+    pub geoip_city: Option<String>,
+    /// GeoLite2 ASN database (.mmdb) to add `asn` to each document
+    pub geoip_asn: Option<String>,
+    /// Where to remember how far each log file has been indexed, so a restart doesn't lose or repeat lines
+    pub state_file: Option<String>,
+    /// Index per day or month instead of one index, like `logger-%Y.%m.%d`. The db in the url becomes the alias to search them all
+    pub index_pattern: Option<String>,
+    /// Keep an index template on the server with the mapping and settings, on unless set to false
+    pub index_template: Option<bool>,
+    pub index_shards: Option<u32>,
+    pub index_replicas: Option<u32>,
+    /// How soon documents can be searched, like `30s`
+    pub refresh_interval: Option<String>,
+    /// Days before an index is made read only and merged down, by an ILM (or ISM in OpenSearch) policy
+    pub ilm_warm_days: Option<u32>,
+    /// Days before an index is deleted by the policy
+    pub ilm_delete_days: Option<u32>,
+    /// Ask the cluster for the rest of its nodes, and send to those too
+    pub sniff: bool,
+    /// Where bulks Elasticsearch couldn't take are kept until it's back
+    pub spool_dir: Option<String>,
+    /// How big the spool can get before the oldest bulks are dropped, in megabytes
+    pub spool_max_mb: Option<u64>,
+    /// Seconds a document can wait for the bulk to fill up before it's sent anyway
+    pub flush_interval: Option<u64>,
+    /// Where documents Elasticsearch rejects for good are written, with the reason
+    pub dead_letter_file: Option<String>,
+    /// Where lines that can't be parsed are written, with why
+    pub unparsed_file: Option<String>,
+    /// Also send lines that can't be parsed to `<index>-unparsed`
+    pub index_unparsed: bool,
+    /// Username for Elasticsearch, `user:pass@` in the url goes before this
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Elasticsearch API key, either `id:key` or the base64 encoded form
+    pub api_key: Option<String>,
+    pub bearer_token: Option<String>,
+    /// PEM file with the CA certificate(s) the Elasticsearch certificate is signed with
+    pub ca_cert: Option<String>,
+    /// PKCS#12 file (.p12) with a client certificate and its key, to log in with
+    pub client_cert: Option<String>,
+    pub client_cert_password: Option<String>,
+    /// Accept any certificate from Elasticsearch, only for testing
+    pub insecure_skip_verify: bool,
+    /// Address to receive nginx's `access_log syslog:server=...` on, like `0.0.0.0:514`
+    pub syslog_udp: Option<String>,
+    /// Address to receive syslog over TCP on, like from rsyslog forwarding
+    pub syslog_tcp: Option<String>
+}
+impl Config {
+    pub fn load(path : &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        serde_json::from_str(text.as_str()).map_err(|e| format!("Invalid config {}: {}", path, e))
+    }
+}
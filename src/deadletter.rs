@@ -0,0 +1,178 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use chrono::Utc;
+use colored::Colorize;
+use serde_json::{json, Value};
+use crate::logger::ParseError;
+use crate::server::Server;
+
+
+/// Used when `dead_letter_file` isn't set in the config file
+pub const DEFAULT_DEAD_LETTER_FILE : &str = "/var/lib/rust-logger/dead-letter.ndjson";
+
+/// Used when `unparsed_file` isn't set in the config file
+pub const DEFAULT_UNPARSED_FILE : &str = "/var/lib/rust-logger/unparsed.ndjson";
+
+/// Size a dead-letter file can grow to before it's rotated, like `unparsed.ndjson` → `unparsed.ndjson.1`
+const MAX_SIZE : u64 = 64 * 1024 * 1024;
+
+/// How many rotated files are kept, the oldest is removed
+const ROTATE_KEEP : usize = 3;
+
+/// Moves `path` to `path.1`, `path.1` to `path.2` and so on, once it's grown past `max_size`
+fn rotate(path : &str, max_size : u64) -> Result<(), String> {
+    if fs::metadata(path).map(|m| m.len()).unwrap_or(0) < max_size {
+        return Ok(());
+    }
+    for i in (1..ROTATE_KEEP).rev() {
+        let from = format!("{}.{}", path, i);
+        if Path::new(from.as_str()).exists() {
+            fs::rename(from.as_str(), format!("{}.{}", path, i + 1)).map_err(|e| format!("Unable to rotate {}: {}", from, e))?;
+        }
+    }
+    fs::rename(path, format!("{}.1", path)).map_err(|e| format!("Unable to rotate {}: {}", path, e))
+}
+
+/// Appends the records to the file, one JSON object per line, so they can be looked at and sent again by hand
+pub fn write(path : &str, records : &[Value]) -> Result<(), String> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+    }
+    rotate(path, MAX_SIZE)?;
+
+    let mut text = String::new();
+    for record in records {
+        text.push_str(record.to_string().as_str());
+        text.push('\n');
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Unable to open {}: {}", path, e))?;
+    file.write_all(text.as_bytes()).map_err(|e| format!("Unable to write {}: {}", path, e))
+}
+
+/// Lines the parser rejected, kept so the log format can be fixed without losing the traffic
+pub struct Unparsed {
+    path: String,
+    /// Also send them to `<index>-unparsed`, so they can be searched
+    index: bool,
+    lines: Vec<Value>
+}
+impl Unparsed {
+    pub fn new(path : &str, index : bool) -> Self {
+        Unparsed {
+            path: String::from(path),
+            index,
+            lines: vec![]
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// `source` is the file or listener the line came from
+    pub fn push(&mut self, source : &str, line : &str, error : &ParseError) {
+        self.lines.push(json!({
+            "time": Utc::now().to_rfc3339(),
+            "source": source,
+            "reason": error.reason(),
+            "error": error.to_string(),
+            "line": line
+        }));
+    }
+
+    /// Writes what's been collected to the file, and to Elasticsearch if enabled.
+    /// They're on disk either way, so failing to index them isn't retried
+    pub async fn flush(&mut self, server : &Server) {
+        if self.lines.is_empty() {
+            return;
+        }
+        println!("{} {}", self.lines.len().to_string().yellow(), "lines couldn't be parsed".yellow());
+        if let Err(e) = write(self.path.as_str(), &self.lines) {
+            println!("{}", e.red());
+        }
+        if self.index {
+            server.bulk_unparsed(&self.lines).await;
+        }
+        self.lines.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+    use std::path::PathBuf;
+    use crate::mock::{Flavor, Mock};
+    use crate::server::is_es;
+
+    fn temp_dir(name : &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust-logger-deadletter-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rotates_past_the_size() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("unparsed.ndjson");
+        let path = path.to_str().unwrap();
+        for (suffix, text) in [("", "current"), (".1", "one"), (".2", "two"), (".3", "three")] {
+            fs::write(format!("{}{}", path, suffix), text).unwrap();
+        }
+
+        // Still small enough
+        rotate(path, 8).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "current");
+
+        rotate(path, 7).unwrap();
+        assert!(!Path::new(path).exists());
+        assert_eq!(fs::read_to_string(format!("{}.1", path)).unwrap(), "current");
+        assert_eq!(fs::read_to_string(format!("{}.2", path)).unwrap(), "one");
+        // Only ROTATE_KEEP are kept, the oldest is gone
+        assert_eq!(fs::read_to_string(format!("{}.{}", path, ROTATE_KEEP)).unwrap(), "two");
+        assert!(!Path::new(format!("{}.{}", path, ROTATE_KEEP + 1).as_str()).exists());
+
+        // Written to a new file from then on
+        write(path, &[json!({"line": "new"})]).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "{\"line\":\"new\"}\n");
+    }
+
+    #[test]
+    fn unparsed_lines_are_kept_with_the_reason() {
+        let mock = Mock::start(Flavor::Elasticsearch7, None);
+        let mut server = Server::new(format!("{}/logger", mock.url).as_str()).unwrap();
+        assert!(tokio::runtime::Runtime::new().unwrap().block_on(is_es(&mut server)));
+        let dir = temp_dir("unparsed");
+        let path = dir.join("unparsed.ndjson");
+
+        let mut unparsed = Unparsed::new(path.to_str().unwrap(), true);
+        unparsed.push("/var/log/nginx/access.log", "unknown - - [...]", &ParseError::BadIp(String::from("unknown")));
+        unparsed.push("/var/log/nginx/access.log", "garbage", &ParseError::Mismatch);
+        assert_eq!(unparsed.len(), 2);
+        tokio::runtime::Runtime::new().unwrap().block_on(unparsed.flush(&server));
+        assert!(unparsed.is_empty());
+
+        let records : Vec<Value> = fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["reason"], "bad_ip");
+        assert_eq!(records[0]["error"], "no client ip in \"unknown\"");
+        assert_eq!(records[0]["source"], "/var/log/nginx/access.log");
+        assert_eq!(records[1]["reason"], "regex_mismatch");
+        assert_eq!(records[1]["line"], "garbage");
+        assert_eq!(mock.documents("logger-unparsed").len(), 2);
+    }
+}
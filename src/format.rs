@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use crate::proxy::{Cidr, default_trusted};
+
+
+/// The layout rust-logger was originally written for, see README
+pub const COMBINED_REALIP : &str = r#"$http_x_forwarded_for - $remote_user [$time_local] "$host" "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+/// The `main` format from the nginx.conf shipped with nginx
+pub const MAIN : &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" "$http_x_forwarded_for""#;
+/// nginx's built in `combined` format
+pub const COMBINED : &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+/// Apache `%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-agent}i"`
+pub const APACHE_COMBINED : &str = r#"$remote_addr $remote_ident $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+/// Apache `%h %l %u %t "%r" %>s %b`
+pub const APACHE_COMMON : &str = r#"$remote_addr $remote_ident $remote_user [$time_local] "$request" $status $body_bytes_sent"#;
+
+/// Built in formats, in the order they are tried when detecting the format of a file.
+/// The more specific ones have to come first, as `main` lines also match `combined`
+pub const PRESETS : [(&str, &str); 5] = [
+    ("combined_realip", COMBINED_REALIP),
+    ("main", MAIN),
+    ("combined", COMBINED),
+    ("apache_combined", APACHE_COMBINED),
+    ("apache_common", APACHE_COMMON)
+];
+
+/// Variables that can be used as the client address, first priority from top to bottom
+pub const IP_VARIABLES : [&str; 3] = ["http_x_forwarded_for", "remote_addr", "realip_remote_addr"];
+/// Variables that can be used as the response size
+pub const SIZE_VARIABLES : [&str; 2] = ["body_bytes_sent", "bytes_sent"];
+/// Variables that can be used as the time, `$msec` is the only one with milliseconds
+pub const TIME_VARIABLES : [&str; 3] = ["msec", "time_iso8601", "time_local"];
+/// Variables that can be used as the host
+pub const HOST_VARIABLES : [&str; 3] = ["host", "http_host", "server_name"];
+/// Every variable Logger stores, anything else is only matched
+pub const KNOWN_VARIABLES : [&str; 18] = [
+    "http_x_forwarded_for", "remote_addr", "realip_remote_addr",
+    "msec", "time_iso8601", "time_local",
+    "host", "http_host", "server_name",
+    "request", "status",
+    "body_bytes_sent", "bytes_sent",
+    "http_referer", "http_user_agent",
+    "request_time", "upstream_response_time", "upstream_addr"
+];
+/// Logger's own field names, accepted as keys in JSON logs instead of the nginx variable
+pub const JSON_ALIASES : [(&str, &str); 7] = [
+    ("ip", "remote_addr"),
+    ("time", "time_local"),
+    ("status_code", "status"),
+    ("size", "body_bytes_sent"),
+    ("refer", "http_referer"),
+    ("referer", "http_referer"),
+    ("user_agent", "http_user_agent")
+];
+
+/// Regex used to find `$variable` and `${variable}` in a log_format
+fn variable_regex() -> Regex {
+    Regex::new(r#"\$\{?([A-Za-z0-9_]+)\}?"#).unwrap()
+}
+
+/// How a single variable should be matched in the log line.
+/// Anything not listed here is matched lazily, so the literal text around it decides where it ends
+fn variable_pattern(variable : &str) -> &'static str {
+    match variable {
+        "status" => r"\d{3}",
+        "body_bytes_sent" | "bytes_sent" | "request_length" => r"\d+|-",
+        "request_time" => r"\d+\.\d+|-",
+        "time_local" => r"\d{2}/[A-Za-z]{3}/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4}",
+        "time_iso8601" => r"\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:[+-]\d{2}:\d{2}|Z)",
+        "msec" => r"\d+\.\d+",
+        "remote_addr" | "realip_remote_addr" | "remote_user" | "remote_ident" => r"\S+",
+        _ => r".*?"
+    }
+}
+
+/// Checks that the variables Logger can't do without are there
+fn check_required(variables : &[String]) -> Result<(), String> {
+    if !IP_VARIABLES.iter().any(|v| variables.iter().any(|var| var == v)) {
+        return Err(format!("log_format needs one of ${}", IP_VARIABLES.join(", $")));
+    }
+    if !SIZE_VARIABLES.iter().any(|v| variables.iter().any(|var| var == v)) {
+        return Err(format!("log_format needs one of ${}", SIZE_VARIABLES.join(", $")));
+    }
+    if !TIME_VARIABLES.iter().any(|v| variables.iter().any(|var| var == v)) {
+        return Err(format!("log_format needs one of ${}", TIME_VARIABLES.join(", $")));
+    }
+    for required in ["request", "status"] {
+        if !variables.iter().any(|var| var == required) {
+            return Err(format!("log_format needs ${}", required));
+        }
+    }
+    Ok(())
+}
+
+/// The variable a JSON key stands for when there is no template to go by
+/// `"$remote_addr"`, `"remote_addr"` and `"ip"` all become `remote_addr`
+fn json_variable(key : &str) -> String {
+    let key = key.trim_start_matches('$');
+    match JSON_ALIASES.iter().find(|(alias, _)| *alias == key) {
+        Some((_, variable)) => String::from(*variable),
+        None => String::from(key)
+    }
+}
+
+/// nginx writes every value as a string, but hand written templates often leave numbers unquoted
+fn json_value(value : &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::from("-"),
+        other => other.to_string()
+    }
+}
+
+/// Turns whatever was pasted from nginx.conf into the bare format string
+/// ```
+/// log_format main '$remote_addr - $remote_user [$time_local] '
+///                 '"$request" $status $body_bytes_sent';
+/// ```
+/// becomes
+/// ```
+/// $remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent
+/// ```
+/// Strings without the `log_format` directive are returned as is
+fn strip_directive(format : &str) -> String {
+    let trimmed = format.trim();
+    if !trimmed.starts_with("log_format") {
+        return String::from(trimmed);
+    }
+
+    // Every quoted part is concatenated, just like nginx does
+    let mut out = String::new();
+    let mut quote : Option<char> = None;
+    let mut escaped = false;
+    for c in trimmed.chars() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    out.push(c);
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                } else {
+                    out.push(c);
+                }
+            }
+            None => {
+                if c == '\'' || c == '"' {
+                    quote = Some(c);
+                }
+            }
+        }
+    }
+    out
+}
+
+#[derive(Clone)]
+enum Matcher {
+    /// Plain text logs, matched with a regex built from the format
+    Pattern {
+        regex : Regex,
+        variables : Vec<String>
+    },
+    /// `escape=json` logs, `keys` maps a JSON key to the variable from the template
+    Json {
+        keys : HashMap<String, String>
+    }
+}
+
+/// A compiled nginx `log_format`, used to turn a log line into its variables
+#[derive(Clone)]
+pub struct LogFormat {
+    name : String,
+    matcher : Matcher,
+    passthrough : bool,
+    /// Not really part of the format, but needed to read the client address out of it
+    trusted_proxies : Vec<Cidr>
+}
+impl LogFormat {
+    /// Compiles a `log_format`, either just the format string or the entire directive from nginx.conf
+    pub fn new(name : &str, format : &str) -> Result<Self, String> {
+        let is_json = format.contains("escape=json");
+        let format = strip_directive(format);
+        if format.is_empty() {
+            return Err(String::from("log_format is empty"));
+        }
+        if is_json || format.starts_with('{') {
+            return LogFormat::json_template(name, format.as_str());
+        }
+
+        let mut pattern = String::from("^");
+        let mut variables : Vec<String> = vec![];
+        let mut last = 0;
+        for cap in variable_regex().captures_iter(format.as_str()) {
+            let whole = cap.get(0).unwrap();
+            let variable = String::from(&cap[1]);
+            pattern.push_str(regex::escape(&format[last..whole.start()]).as_str());
+            pattern.push_str(format!("({})", variable_pattern(variable.as_str())).as_str());
+            variables.push(variable);
+            last = whole.end();
+        }
+        pattern.push_str(regex::escape(&format[last..]).as_str());
+        pattern.push('$');
+        check_required(&variables)?;
+
+        let regex = Regex::new(pattern.as_str()).map_err(|e| e.to_string())?;
+        Ok(LogFormat {
+            name: String::from(name),
+            matcher: Matcher::Pattern {
+                regex,
+                variables
+            },
+            passthrough: false,
+            trusted_proxies: default_trusted()
+        })
+    }
+
+    /// JSON logs without a template, keys are expected to be named after the variable
+    /// ```
+    /// {"remote_addr":"127.0.0.1","time_local":"17/Sep/2022:23:39:19 +0200","request":"GET / HTTP/1.1",...}
+    /// ```
+    pub fn json() -> Self {
+        LogFormat {
+            name: String::from("json"),
+            matcher: Matcher::Json {
+                keys: HashMap::new()
+            },
+            passthrough: false,
+            trusted_proxies: default_trusted()
+        }
+    }
+
+    /// JSON logs where the keys are taken from the template
+    /// ```
+    /// {"client":"$remote_addr","ts":"$time_local","req":"$request","code":$status,...}
+    /// ```
+    fn json_template(name : &str, format : &str) -> Result<Self, String> {
+        let re = Regex::new(r#""([^"]+)"\s*:\s*"?\$\{?([A-Za-z0-9_]+)\}?"?"#).unwrap();
+        let mut keys : HashMap<String, String> = HashMap::new();
+        for cap in re.captures_iter(format) {
+            keys.insert(String::from(&cap[1]), String::from(&cap[2]));
+        }
+        let variables : Vec<String> = keys.values().cloned().collect();
+        check_required(&variables)?;
+
+        Ok(LogFormat {
+            name: String::from(name),
+            matcher: Matcher::Json {
+                keys
+            },
+            passthrough: false,
+            trusted_proxies: default_trusted()
+        })
+    }
+
+    /// Uses the preset if `format` is the name of one, otherwise compiles it as a custom format
+    pub fn from_config(format : &str) -> Result<Self, String> {
+        if format.trim() == "json" {
+            return Ok(LogFormat::json());
+        }
+        match PRESETS.iter().find(|(name, _)| *name == format.trim()) {
+            Some((name, preset)) => LogFormat::new(name, preset),
+            None => LogFormat::new("custom", format)
+        }
+    }
+
+    /// Every built in format, in the order of [PRESETS], and then json
+    pub fn presets() -> Vec<Self> {
+        let mut presets : Vec<Self> = PRESETS.iter()
+            .map(|(name, format)| LogFormat::new(name, format).unwrap())
+            .collect();
+        presets.push(LogFormat::json());
+        presets
+    }
+
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn is_json(&self) -> bool {
+        matches!(self.matcher, Matcher::Json { .. })
+    }
+
+    /// Keep JSON keys Logger doesn't know about in the document, instead of dropping them
+    pub fn set_passthrough(&mut self, passthrough : bool) {
+        self.passthrough = passthrough;
+    }
+
+    pub fn get_passthrough(&self) -> bool {
+        self.passthrough
+    }
+
+    pub fn set_trusted_proxies(&mut self, trusted_proxies : Vec<Cidr>) {
+        self.trusted_proxies = trusted_proxies;
+    }
+
+    pub fn get_trusted_proxies(&self) -> &[Cidr] {
+        &self.trusted_proxies
+    }
+
+    /// The variable a JSON key stands for
+    fn variable(&self, key : &str) -> String {
+        match &self.matcher {
+            Matcher::Json { keys } if !keys.is_empty() => keys.get(key).cloned().unwrap_or_else(|| String::from(key)),
+            _ => json_variable(key)
+        }
+    }
+
+    /// Matches the line, and returns every variable with its value.
+    /// If a variable is used more than once, the first occurrence is kept
+    pub fn captures(&self, line : &str) -> Option<HashMap<String, String>> {
+        let mut fields : HashMap<String, String> = HashMap::new();
+        match &self.matcher {
+            Matcher::Pattern { regex, variables } => {
+                let cap = regex.captures(line)?;
+                for (i, variable) in variables.iter().enumerate() {
+                    if let Some(value) = cap.get(i + 1) {
+                        fields.entry(variable.clone()).or_insert_with(|| String::from(value.as_str()));
+                    }
+                }
+            }
+            Matcher::Json { .. } => {
+                let object : Map<String, Value> = serde_json::from_str(line).ok()?;
+                for (key, value) in object.iter() {
+                    fields.entry(self.variable(key)).or_insert_with(|| json_value(value));
+                }
+            }
+        }
+        Some(fields)
+    }
+
+    /// JSON keys that don't map to any variable Logger stores, with their original value
+    pub fn unknown(&self, line : &str) -> Map<String, Value> {
+        if !self.is_json() {
+            return Map::new();
+        }
+        let mut object : Map<String, Value> = serde_json::from_str(line).unwrap_or_default();
+        object.retain(|key, _| !KNOWN_VARIABLES.contains(&self.variable(key).as_str()));
+        object
+    }
+
+    /// Explains why a JSON line can't be used, None if it has everything Logger needs
+    pub fn missing(&self, line : &str) -> Option<String> {
+        let fields = match self.captures(line) {
+            Some(fields) => fields,
+            None => return Some(String::from("not a JSON object"))
+        };
+        let variables : Vec<String> = fields.into_keys().collect();
+        check_required(&variables).err().map(|e| e.replace("log_format needs", "missing"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME : &str = "20/Jul/2022:22:12:47 +0200";
+
+    /// A line for each preset, one that only that preset reads first
+    fn lines() -> Vec<(&'static str, String)> {
+        vec![
+            ("combined_realip", format!(r#"127.0.0.1, 84.213.100.23 - - [{}] "knaben.ru" "GET /index.html HTTP/1.1" 403 153 "https://google.com/q=test" "Mozilla/5.0""#, TIME)),
+            ("main", format!(r#"84.213.100.23 - - [{}] "GET /index.html HTTP/1.1" 403 153 "https://google.com/q=test" "Mozilla/5.0" "10.0.0.1""#, TIME)),
+            ("combined", format!(r#"84.213.100.23 - - [{}] "GET /index.html HTTP/1.1" 403 153 "https://google.com/q=test" "Mozilla/5.0""#, TIME)),
+            ("apache_combined", format!(r#"84.213.100.23 ident frank [{}] "GET /index.html HTTP/1.1" 403 - "-" "Mozilla/5.0""#, TIME)),
+            ("apache_common", format!(r#"84.213.100.23 - - [{}] "GET /index.html HTTP/1.1" 403 153"#, TIME))
+        ]
+    }
+
+    #[test]
+    fn every_preset_reads_its_own_line() {
+        for (name, line) in lines() {
+            let format = LogFormat::from_config(name).unwrap();
+            assert_eq!(format.get_name(), name);
+            let fields = format.captures(line.as_str()).unwrap_or_else(|| panic!("{} didn't match", name));
+            assert_eq!(fields["time_local"], TIME, "{}", name);
+            assert_eq!(fields["request"], "GET /index.html HTTP/1.1", "{}", name);
+            assert_eq!(fields["status"], "403", "{}", name);
+        }
+    }
+
+    #[test]
+    fn presets_are_detected_in_order() {
+        let presets = LogFormat::presets();
+        for (name, line) in lines() {
+            let format = crate::logger::detect_format(line.as_str(), &presets).unwrap_or_else(|| panic!("nothing read {}", name));
+            assert_eq!(format.get_name(), name);
+        }
+    }
+
+    #[test]
+    fn main_lines_also_match_combined() {
+        // Why main has to be tried before combined
+        let (_, line) = &lines()[1];
+        let combined = LogFormat::from_config("combined").unwrap();
+        assert!(combined.captures(line.as_str()).is_some());
+    }
+
+    #[test]
+    fn directive_from_nginx_conf() {
+        let format = LogFormat::new("custom", r#"log_format main '$remote_addr - $remote_user [$time_local] '
+                    '"$request" $status $body_bytes_sent';"#).unwrap();
+        let fields = format.captures(format!(r#"10.0.0.1 - - [{}] "GET / HTTP/1.1" 200 512"#, TIME).as_str()).unwrap();
+        assert_eq!(fields["remote_addr"], "10.0.0.1");
+        assert_eq!(fields["body_bytes_sent"], "512");
+    }
+
+    #[test]
+    fn required_variables() {
+        assert!(LogFormat::new("custom", "$remote_addr [$time_local] \"$request\" $status").err().unwrap().contains("body_bytes_sent"));
+        assert!(LogFormat::new("custom", "[$time_local] \"$request\" $status $body_bytes_sent").err().unwrap().contains("remote_addr"));
+        assert!(LogFormat::new("custom", "").is_err());
+    }
+
+    #[test]
+    fn json_template() {
+        let format = LogFormat::new("custom", r#"log_format json escape=json '{"client":"$remote_addr","ts":"$time_local",'
+            '"req":"$request","code":$status,"bytes":$body_bytes_sent,"id":"$request_id"}';"#).unwrap();
+        assert!(format.is_json());
+
+        let line = format!(r#"{{"client":"10.0.0.1","ts":"{}","req":"GET / HTTP/1.1","code":200,"bytes":512,"id":"abc","extra":null}}"#, TIME);
+        let fields = format.captures(line.as_str()).unwrap();
+        assert_eq!(fields["remote_addr"], "10.0.0.1");
+        assert_eq!(fields["time_local"], TIME);
+        assert_eq!(fields["status"], "200");
+        assert_eq!(fields["body_bytes_sent"], "512");
+        assert_eq!(fields["extra"], "-");
+
+        let unknown = format.unknown(line.as_str());
+        let mut keys : Vec<&String> = unknown.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["extra", "id"]);
+
+        assert_eq!(format.missing(r#"{"client":"10.0.0.1","ts":"x","req":"GET / HTTP/1.1","bytes":1}"#).unwrap(), "missing $status");
+        assert_eq!(format.missing("not json").unwrap(), "not a JSON object");
+    }
+
+    #[test]
+    fn json_without_template() {
+        let format = LogFormat::from_config("json").unwrap();
+        let fields = format.captures(r#"{"ip":"10.0.0.1","$status":"200","size":1}"#).unwrap();
+        assert_eq!(fields["remote_addr"], "10.0.0.1");
+        assert_eq!(fields["status"], "200");
+        assert_eq!(fields["body_bytes_sent"], "1");
+    }
+}
@@ -0,0 +1,584 @@
+use std::fs;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+
+
+/// Marks the start of the metadata, which sits at the end of the file
+const METADATA_MARKER : &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+/// How deep maps and arrays can be nested, libmaxminddb stops at the same depth.
+/// Keeps a corrupt file from recursing until the stack runs out
+const MAX_DEPTH : usize = 512;
+
+/// How often to check if the database has been replaced on disk
+const RELOAD_INTERVAL : Duration = Duration::from_secs(60);
+
+/// Where the ip is located, from a GeoLite2 City database
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Geo {
+    pub country_iso: Option<String>,
+    pub city: Option<String>,
+    pub location: Option<Location>
+}
+
+/// Stored as an object, which is one of the layouts Elasticsearch accepts for a `geo_point`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Location {
+    pub lat: f64,
+    pub lon: f64
+}
+
+/// Who owns the ip, from a GeoLite2 ASN database
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Asn {
+    pub number: Option<u32>,
+    pub org: Option<String>
+}
+
+/// A MaxMind DB file, read into memory.
+///
+/// The file is a binary search tree over the bits of the ip, followed by a data section the leaves point into,
+/// and the metadata at the very end. See <https://maxmind.github.io/MaxMind-DB/>
+struct Mmdb {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// Where the data section starts, right after the tree and 16 zero bytes
+    data_start: usize,
+    /// The node IPv4 addresses start from in an IPv6 tree, after 96 zero bits
+    ipv4_start: usize
+}
+impl Mmdb {
+    fn open(path : &str) -> Result<Self, String> {
+        let buf = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        let marker = buf.windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or(format!("{} is not a MaxMind DB file", path))?;
+
+        let metadata = Decoder { buf: &buf, start: 0 }
+            .decode(marker + METADATA_MARKER.len())
+            .map(|(value, _)| value)
+            .ok_or(format!("Invalid metadata in {}", path))?;
+        let node_count = metadata["node_count"].as_u64().ok_or(format!("No node_count in {}", path))? as usize;
+        let record_size = metadata["record_size"].as_u64().ok_or(format!("No record_size in {}", path))? as usize;
+        let ip_version = metadata["ip_version"].as_u64().unwrap_or(6);
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("Unsupported record size {} in {}", record_size, path));
+        }
+
+        let data_start = node_count * record_size / 4 + 16;
+        if data_start > buf.len() {
+            return Err(format!("{} is truncated", path));
+        }
+
+        let mut mmdb = Mmdb {
+            buf,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+            ipv4_start: 0
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = mmdb.record(node, 0);
+            }
+            mmdb.ipv4_start = node;
+        }
+        Ok(mmdb)
+    }
+
+    /// Reads the left (0) or right (1) record of a node
+    fn record(&self, node : usize, bit : u8) -> usize {
+        let b = &self.buf[node * self.record_size / 4..];
+        match (self.record_size, bit) {
+            (24, 0) => u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize,
+            (24, _) => u32::from_be_bytes([0, b[3], b[4], b[5]]) as usize,
+            (28, 0) => u32::from_be_bytes([(b[3] & 0xF0) >> 4, b[0], b[1], b[2]]) as usize,
+            (28, _) => u32::from_be_bytes([b[3] & 0x0F, b[4], b[5], b[6]]) as usize,
+            (_, 0) => u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize,
+            (_, _) => u32::from_be_bytes([b[4], b[5], b[6], b[7]]) as usize
+        }
+    }
+
+    fn lookup(&self, ip : &IpAddr) -> Option<Value> {
+        let (bits, mut node) : (Vec<u8>, usize) = match ip {
+            IpAddr::V4(ip) => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(ip) => {
+                if self.ip_version == 4 {
+                    return None;
+                }
+                (ip.octets().to_vec(), 0)
+            }
+        };
+
+        for i in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bits[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit);
+        }
+
+        // Equal to node_count means there's nothing for this ip, and the 16 bytes after the tree aren't data either
+        let offset = node.checked_sub(self.node_count + 16)?;
+        Decoder { buf: &self.buf, start: self.data_start }
+            .decode(self.data_start + offset)
+            .map(|(value, _)| value)
+    }
+}
+
+/// Turns the data section into json, returning the value and the position after it
+struct Decoder<'a> {
+    buf: &'a [u8],
+    /// Pointers are relative to the start of the data section
+    start: usize
+}
+impl<'a> Decoder<'a> {
+    fn bytes(&self, pos : usize, size : usize) -> Option<&'a [u8]> {
+        self.buf.get(pos..pos + size)
+    }
+
+    fn uint(&self, pos : usize, size : usize) -> Option<u64> {
+        let bytes = self.bytes(pos, size)?;
+        Some(bytes.iter().fold(0u64, |n, b| (n << 8) | *b as u64))
+    }
+
+    fn decode(&self, pos : usize) -> Option<(Value, usize)> {
+        self.value(pos, 0)
+    }
+
+    fn value(&self, pos : usize, depth : usize) -> Option<(Value, usize)> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let control = *self.buf.get(pos)?;
+        let mut pos = pos + 1;
+        let mut kind = control >> 5;
+
+        if kind == 1 {
+            let size = ((control >> 3) & 0x3) as usize;
+            let value = (control & 0x7) as u64;
+            let pointer = match size {
+                0 => (value << 8) | self.uint(pos, 1)?,
+                1 => ((value << 16) | self.uint(pos, 2)?) + 2048,
+                2 => ((value << 24) | self.uint(pos, 3)?) + 526336,
+                _ => self.uint(pos, 4)?
+            };
+            // A pointer can't point to another pointer, only a corrupt file does that
+            let target = self.start + pointer as usize;
+            if *self.buf.get(target)? >> 5 == 1 {
+                return None;
+            }
+            let (value, _) = self.value(target, depth + 1)?;
+            return Some((value, pos + size + 1));
+        }
+
+        if kind == 0 {
+            kind = 7 + *self.buf.get(pos)?;
+            pos += 1;
+        }
+
+        let mut size = (control & 0x1f) as usize;
+        match size {
+            29 => { size = 29 + self.uint(pos, 1)? as usize; pos += 1; }
+            30 => { size = 285 + self.uint(pos, 2)? as usize; pos += 2; }
+            31 => { size = 65821 + self.uint(pos, 3)? as usize; pos += 3; }
+            _ => {}
+        }
+
+        match kind {
+            // utf8 string
+            2 => {
+                let str = String::from_utf8_lossy(self.bytes(pos, size)?).to_string();
+                Some((Value::String(str), pos + size))
+            }
+            // double
+            3 => {
+                let bytes : [u8; 8] = self.bytes(pos, 8)?.try_into().ok()?;
+                Some((Number::from_f64(f64::from_be_bytes(bytes)).map(Value::Number).unwrap_or(Value::Null), pos + 8))
+            }
+            // bytes, not used by any of the fields we read
+            4 => Some((Value::Null, pos + size)),
+            // uint16, uint32, uint64
+            5 | 6 | 9 => Some((Value::from(self.uint(pos, size)?), pos + size)),
+            // map
+            7 => {
+                let mut map = Map::new();
+                for _ in 0..size {
+                    let (key, next) = self.value(pos, depth + 1)?;
+                    let (value, next) = self.value(next, depth + 1)?;
+                    pos = next;
+                    if let Value::String(key) = key {
+                        map.insert(key, value);
+                    }
+                }
+                Some((Value::Object(map), pos))
+            }
+            // int32, only negative when all 4 bytes are used
+            8 => Some((Value::from(self.uint(pos, size)? as u32 as i32), pos + size)),
+            // uint128, too big for json so kept as a string
+            10 => {
+                let bytes = self.bytes(pos, size)?;
+                let n = bytes.iter().fold(0u128, |n, b| (n << 8) | *b as u128);
+                Some((Value::String(n.to_string()), pos + size))
+            }
+            // array
+            11 => {
+                let mut array = Vec::new();
+                for _ in 0..size {
+                    let (value, next) = self.value(pos, depth + 1)?;
+                    pos = next;
+                    array.push(value);
+                }
+                Some((Value::Array(array), pos))
+            }
+            // boolean, the value is the size
+            14 => Some((Value::Bool(size != 0), pos)),
+            // float
+            15 => {
+                let bytes : [u8; 4] = self.bytes(pos, 4)?.try_into().ok()?;
+                Some((Number::from_f64(f32::from_be_bytes(bytes) as f64).map(Value::Number).unwrap_or(Value::Null), pos + 4))
+            }
+            _ => None
+        }
+    }
+}
+
+/// A database file that's opened again when it's replaced, like by geoipupdate
+struct Database {
+    path: String,
+    modified: Option<SystemTime>,
+    checked: Instant,
+    mmdb: Mmdb
+}
+impl Database {
+    fn open(path : &str) -> Result<Self, String> {
+        Ok(Database {
+            path: String::from(path),
+            modified: fs::metadata(path).and_then(|m| m.modified()).ok(),
+            checked: Instant::now(),
+            mmdb: Mmdb::open(path)?
+        })
+    }
+
+    fn reload(&mut self) {
+        if self.checked.elapsed() < RELOAD_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+
+        let modified = fs::metadata(self.path.as_str()).and_then(|m| m.modified()).ok();
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+        // A half written file fails to open, so keep the old one and try again next time
+        match Mmdb::open(self.path.as_str()) {
+            Ok(mmdb) => {
+                println!("Reloaded {}", self.path);
+                self.mmdb = mmdb;
+                self.modified = modified;
+            }
+            Err(e) => println!("Unable to reload {}: {}", self.path, e)
+        }
+    }
+
+    fn lookup(&mut self, ip : &IpAddr) -> Option<Value> {
+        self.reload();
+        self.mmdb.lookup(ip)
+    }
+}
+
+/// Looks up ips in local GeoLite2 City and ASN databases, either of them can be left out
+pub struct GeoIp {
+    city: Option<Database>,
+    asn: Option<Database>
+}
+impl GeoIp {
+    pub fn new(city : Option<&str>, asn : Option<&str>) -> Result<Self, String> {
+        Ok(GeoIp {
+            city: city.map(Database::open).transpose()?,
+            asn: asn.map(Database::open).transpose()?
+        })
+    }
+
+    pub fn city(&mut self, ip : &IpAddr) -> Option<Geo> {
+        let record = self.city.as_mut()?.lookup(ip)?;
+        let country = if record["country"].is_object() { &record["country"] } else { &record["registered_country"] };
+
+        let mut location = None;
+        if let (Some(lat), Some(lon)) = (record["location"]["latitude"].as_f64(), record["location"]["longitude"].as_f64()) {
+            location = Some(Location { lat, lon });
+        }
+        Some(Geo {
+            country_iso: country["iso_code"].as_str().map(String::from),
+            city: record["city"]["names"]["en"].as_str().map(String::from),
+            location
+        })
+    }
+
+    pub fn asn(&mut self, ip : &IpAddr) -> Option<Asn> {
+        let record = self.asn.as_mut()?.lookup(ip)?;
+        Some(Asn {
+            number: record["autonomous_system_number"].as_u64().map(|n| n as u32),
+            org: record["autonomous_system_organization"].as_str().map(String::from)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+    use serde_json::json;
+
+    /// The start of a field, with its type and size
+    fn control(kind : u8, size : usize) -> Vec<u8> {
+        let (size, extra) = match size {
+            0..=28 => (size as u8, vec![]),
+            29..=284 => (29, vec![(size - 29) as u8]),
+            285..=65820 => (30, ((size - 285) as u16).to_be_bytes().to_vec()),
+            _ => (31, ((size - 65821) as u32).to_be_bytes()[1..].to_vec())
+        };
+        let mut bytes = match kind {
+            0..=7 => vec![(kind << 5) | size],
+            _ => vec![size, kind - 7]
+        };
+        bytes.extend(extra);
+        bytes
+    }
+
+    /// A pointer to `offset` in the data section, in the smallest of the four sizes
+    fn pointer(offset : usize) -> Vec<u8> {
+        assert!(offset < 2048);
+        vec![(1 << 5) | (offset >> 8) as u8, offset as u8]
+    }
+
+    fn encode(value : &Value) -> Vec<u8> {
+        match value {
+            Value::String(str) => [control(2, str.len()), str.as_bytes().to_vec()].concat(),
+            Value::Number(n) if n.is_f64() => [control(3, 8), n.as_f64().unwrap().to_be_bytes().to_vec()].concat(),
+            Value::Number(n) => {
+                let bytes : Vec<u8> = n.as_u64().unwrap().to_be_bytes().into_iter().skip_while(|b| *b == 0).collect();
+                [control(6, bytes.len()), bytes].concat()
+            }
+            Value::Bool(b) => control(14, *b as usize),
+            Value::Array(array) => [control(11, array.len()), array.iter().flat_map(encode).collect()].concat(),
+            Value::Object(map) => [control(7, map.len()), map.iter().flat_map(|(key, value)| [encode(&json!(key)), encode(value)].concat()).collect()].concat(),
+            Value::Null => unreachable!()
+        }
+    }
+
+    /// Writes a MaxMind DB by the spec. Each network points at its offset in `data`,
+    /// IPv4 networks are put in the IPv6 tree after 96 zero bits, like MaxMind does
+    fn build(record_size : usize, networks : &[(&str, usize)], data : &[u8]) -> Vec<u8> {
+        enum Record { Empty, Node(usize), Data(usize) }
+        let mut nodes : Vec<[Record; 2]> = vec![[Record::Empty, Record::Empty]];
+        for (network, offset) in networks {
+            let (address, prefix) = network.split_once('/').unwrap();
+            let (bits, prefix) = match address.parse::<IpAddr>().unwrap() {
+                IpAddr::V4(ip) => (u32::from(ip) as u128, prefix.parse::<usize>().unwrap() + 96),
+                IpAddr::V6(ip) => (u128::from(ip), prefix.parse::<usize>().unwrap())
+            };
+            let mut node = 0;
+            for i in 0..prefix {
+                let bit = ((bits >> (127 - i)) & 1) as usize;
+                if i == prefix - 1 {
+                    nodes[node][bit] = Record::Data(*offset);
+                } else if let Record::Node(next) = nodes[node][bit] {
+                    node = next;
+                } else {
+                    nodes.push([Record::Empty, Record::Empty]);
+                    nodes[node][bit] = Record::Node(nodes.len() - 1);
+                    node = nodes.len() - 1;
+                }
+            }
+        }
+
+        let node_count = nodes.len();
+        let value = |record : &Record| match record {
+            Record::Empty => node_count as u32,
+            Record::Node(node) => *node as u32,
+            Record::Data(offset) => (node_count + 16 + offset) as u32
+        };
+        let mut file = vec![];
+        for [left, right] in nodes.iter() {
+            let (left, right) = (value(left).to_be_bytes(), value(right).to_be_bytes());
+            match record_size {
+                24 => file.extend([&left[1..], &right[1..]].concat()),
+                28 => file.extend([&left[1..], &[(left[0] << 4) | right[0]], &right[1..]].concat()),
+                _ => file.extend([left, right].concat())
+            }
+        }
+        file.extend([0; 16]);
+        file.extend(data);
+        file.extend(METADATA_MARKER);
+        file.extend(encode(&json!({
+            "node_count": node_count,
+            "record_size": record_size,
+            "ip_version": 6,
+            "database_type": "rust-logger-test",
+            "binary_format_major_version": 2,
+            "binary_format_minor_version": 0
+        })));
+        file
+    }
+
+    /// A City and an ASN database. The continent is a string shared through a pointer, like in the real ones
+    fn databases(record_size : usize) -> (Vec<u8>, Vec<u8>) {
+        let mut city = encode(&json!("Europe"));
+        let oslo = city.len();
+        city.extend([control(7, 4), encode(&json!("continent")), pointer(0)].concat());
+        city.extend(encode(&json!("country")));
+        city.extend(encode(&json!({"iso_code": "NO"})));
+        city.extend(encode(&json!("city")));
+        city.extend(encode(&json!({"names": {"en": "Oslo", "nb": "Oslo"}})));
+        city.extend(encode(&json!("location")));
+        city.extend(encode(&json!({"latitude": 59.9127, "longitude": 10.7461, "accuracy_radius": 20})));
+        // Anycast and such only have the country it's registered to
+        let stockholm = city.len();
+        city.extend(encode(&json!({"registered_country": {"iso_code": "SE"}, "location": {"latitude": 59.3, "longitude": 18.0}})));
+        let city = build(record_size, &[("81.2.69.0/24", oslo), ("2001:db8::/32", stockholm)], &city);
+
+        let asn = encode(&json!({"autonomous_system_number": 4200000001u64, "autonomous_system_organization": "Example AS"}));
+        let asn = build(record_size, &[("81.2.0.0/16", 0)], &asn);
+        (city, asn)
+    }
+
+    fn temp_file(name : &str, bytes : &[u8]) -> String {
+        let path : PathBuf = env::temp_dir().join(format!("rust-logger-{}-{}.mmdb", name, process::id()));
+        fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn ip(str : &str) -> IpAddr {
+        str.parse().unwrap()
+    }
+
+    #[test]
+    fn every_record_size() {
+        for record_size in [24, 28, 32] {
+            let (city, asn) = databases(record_size);
+            let city = temp_file(format!("city-{}", record_size).as_str(), &city);
+            let asn = temp_file(format!("asn-{}", record_size).as_str(), &asn);
+            let mut geoip = GeoIp::new(Some(city.as_str()), Some(asn.as_str())).unwrap();
+
+            let geo = geoip.city(&ip("81.2.69.160")).unwrap();
+            assert_eq!(geo.country_iso.as_deref(), Some("NO"), "record size {}", record_size);
+            assert_eq!(geo.city.as_deref(), Some("Oslo"));
+            let location = geo.location.unwrap();
+            assert_eq!((location.lat, location.lon), (59.9127, 10.7461));
+
+            let geo = geoip.city(&ip("2001:db8::1")).unwrap();
+            assert_eq!(geo.country_iso.as_deref(), Some("SE"));
+            assert_eq!(geo.city, None);
+
+            let asn = geoip.asn(&ip("81.2.1.1")).unwrap();
+            assert_eq!(asn.number, Some(4200000001));
+            assert_eq!(asn.org.as_deref(), Some("Example AS"));
+
+            assert!(geoip.city(&ip("81.2.70.1")).is_none());
+            assert!(geoip.city(&ip("10.0.0.1")).is_none());
+            assert!(geoip.asn(&ip("2001:db8::1")).is_none());
+        }
+    }
+
+    #[test]
+    fn never_locates_trusted_proxies() {
+        use crate::format::{LogFormat, COMBINED_REALIP};
+        use crate::logger::Logger;
+        use crate::proxy::Cidr;
+
+        let (city, _) = databases(24);
+        let city = temp_file("proxies", &city);
+        let mut geoip = GeoIp::new(Some(city.as_str()), None).unwrap();
+        let trusted = vec![Cidr::new("81.2.69.0/24").unwrap()];
+        let mut format = LogFormat::new("combined_realip", COMBINED_REALIP).unwrap();
+        format.set_trusted_proxies(trusted.clone());
+        let mut locate = |forwarded : &str| {
+            let line = format!(r#"{} - - [18/Oct/2022:08:41:00 +0000] "example.com" "GET / HTTP/1.1" 200 12 "-" "curl/7.0""#, forwarded);
+            let mut logger = Logger::new(line, &format).unwrap();
+            logger.locate(&mut geoip, &trusted);
+            serde_json::to_value(&logger).unwrap()["geo"]["country_iso"].clone()
+        };
+
+        // A private client behind the CDN isn't placed where the CDN is
+        assert_eq!(locate("10.0.0.9, 81.2.69.160"), Value::Null);
+        // But what the client's side of the chain added is tried
+        assert_eq!(locate("2001:db8::1, 10.0.0.9, 81.2.69.160"), json!("SE"));
+    }
+
+    #[test]
+    fn pointers_are_followed() {
+        let (city, _) = databases(24);
+        let mmdb = Mmdb::open(temp_file("pointers", &city).as_str()).unwrap();
+        let record = mmdb.lookup(&ip("81.2.69.160")).unwrap();
+        assert_eq!(record["continent"], "Europe");
+        assert_eq!(record["location"]["accuracy_radius"], 20);
+    }
+
+    #[test]
+    fn not_a_database() {
+        assert!(Mmdb::open(temp_file("garbage", b"not a MaxMind DB").as_str()).is_err());
+        assert!(Mmdb::open("/nonexistent/GeoLite2-City.mmdb").is_err());
+
+        // Claims more nodes than the file has
+        let (mut city, _) = databases(24);
+        let tree = city.windows(METADATA_MARKER.len()).rposition(|w| w == METADATA_MARKER).unwrap();
+        city.drain(..tree);
+        assert!(Mmdb::open(temp_file("truncated", &city).as_str()).err().unwrap().ends_with("is truncated"));
+    }
+
+    #[test]
+    fn record_pointing_between_tree_and_data() {
+        let city = build(24, &[("81.2.69.0/24", 0)], &encode(&json!({"country": {"iso_code": "NO"}})));
+        let mmdb = Mmdb::open(temp_file("separator", &city).as_str()).unwrap();
+        assert!(mmdb.lookup(&ip("81.2.69.160")).is_some());
+
+        // Every value from node_count + 1 to node_count + 15 points into the separator
+        let node_count = mmdb.node_count;
+        let data = &((node_count + 16) as u32).to_be_bytes()[1..];
+        let record = city[..node_count * 6].chunks(3).position(|record| record == data).unwrap();
+        for value in node_count + 1..node_count + 16 {
+            let mut broken = city.clone();
+            broken[record * 3..record * 3 + 3].copy_from_slice(&(value as u32).to_be_bytes()[1..]);
+            let mmdb = Mmdb::open(temp_file("separator", &broken).as_str()).unwrap();
+            assert!(mmdb.lookup(&ip("81.2.69.160")).is_none(), "record {}", value);
+        }
+    }
+
+    #[test]
+    fn pointer_to_pointer() {
+        let data = [pointer(2), pointer(0)].concat();
+        let mmdb = Mmdb::open(temp_file("pointer-to-pointer", &build(24, &[("81.2.69.0/24", 2)], &data)).as_str()).unwrap();
+        assert!(mmdb.lookup(&ip("81.2.69.160")).is_none());
+    }
+
+    #[test]
+    fn self_referencing_map() {
+        // {"a": <pointer back to this map>}
+        let data = [control(7, 1), encode(&json!("a")), pointer(0)].concat();
+        let mmdb = Mmdb::open(temp_file("self-referencing", &build(24, &[("81.2.69.0/24", 0)], &data)).as_str()).unwrap();
+        assert!(mmdb.lookup(&ip("81.2.69.160")).is_none());
+    }
+
+    #[test]
+    fn too_deeply_nested() {
+        let mut data = vec![];
+        for _ in 0..MAX_DEPTH + 10 {
+            data.extend(control(11, 1));
+        }
+        data.extend(encode(&json!("deep")));
+        let mmdb = Mmdb::open(temp_file("nested", &build(24, &[("81.2.69.0/24", 0)], &data)).as_str()).unwrap();
+        assert!(mmdb.lookup(&ip("81.2.69.160")).is_none());
+    }
+}
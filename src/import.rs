@@ -0,0 +1,265 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use colored::Colorize;
+use flate2::read::MultiGzDecoder;
+use crate::format::LogFormat;
+use crate::logger::Logger;
+use crate::server::Server;
+use crate::deadletter::Unparsed;
+
+
+/// Rotated logs compressed by logrotate
+pub fn is_gzip(path : &str) -> bool {
+    path.ends_with(".gz")
+}
+
+/// How many bytes of the file itself have been read
+type BytesRead = Rc<Cell<u64>>;
+
+/// Counts the bytes read from the file itself, so progress works the same for compressed files
+struct Counted {
+    file: File,
+    read: BytesRead
+}
+impl Read for Counted {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        let len = self.file.read(buf)?;
+        self.read.set(self.read.get() + len as u64);
+        Ok(len)
+    }
+}
+
+/// Opens a log file from the start, decompressing it if it's gzipped
+pub fn open_log(path : &str) -> io::Result<Box<dyn BufRead>> {
+    let (reader, _) = open_counted(path)?;
+    Ok(reader)
+}
+
+fn open_counted(path : &str) -> io::Result<(Box<dyn BufRead>, BytesRead)> {
+    let read = Rc::new(Cell::new(0));
+    let counted = Counted {
+        file: File::open(path)?,
+        read: Rc::clone(&read)
+    };
+    if is_gzip(path) {
+        return Ok((Box::new(BufReader::new(MultiGzDecoder::new(counted))), read));
+    }
+    Ok((Box::new(BufReader::new(counted)), read))
+}
+
+/// What came of importing a single file
+#[derive(Debug, Default, PartialEq)]
+struct Imported {
+    lines: usize,
+    sent: usize,
+    /// Lines that couldn't be parsed
+    failed: usize,
+    /// False if any of it couldn't be read or sent
+    complete: bool
+}
+
+/// Reads every file from start to end and bulks it, instead of following them.
+/// Documents already in Elasticsearch get the same id, so importing a file twice doesn't duplicate anything.
+/// Returns false if any of it couldn't be read or sent
+pub fn import(server : &Server, sources : &[(String, LogFormat)], bulk_size : usize, enrich : &mut dyn FnMut(&mut Logger), unparsed : &mut Unparsed, stop : &AtomicBool) -> bool {
+    let mut success = true;
+    for (path, format) in sources {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        success &= import_file(server, path, format, bulk_size, enrich, unparsed, stop).complete;
+    }
+    success
+}
+
+fn import_file(server : &Server, path : &str, format : &LogFormat, bulk_size : usize, enrich : &mut dyn FnMut(&mut Logger), unparsed : &mut Unparsed, stop : &AtomicBool) -> Imported {
+    let mut imported = Imported { complete: true, ..Imported::default() };
+    let (reader, read) = match open_counted(path) {
+        Ok(opened) => opened,
+        Err(e) => {
+            println!("{} {}: {}", "Unable to open".red(), path.red(), e);
+            imported.complete = false;
+            return imported;
+        }
+    };
+    let size = File::open(path).and_then(|f| f.metadata()).map(|m| m.len()).unwrap_or(0).max(1);
+
+    let mut log : Vec<Logger> = vec![];
+    let progress = |imported : &Imported| {
+        let percentage = (read.get() as f64 / size as f64 * 100.0).min(100.0);
+        println!("{} {:.1}% ({} sent, {} failed to parse)", path, percentage, imported.sent, imported.failed);
+    };
+
+    for line in reader.split(b'\n') {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let line = match line {
+            Ok(line) => String::from_utf8_lossy(&line).trim_end_matches('\r').to_string(),
+            Err(e) => {
+                println!("{} {}: {}", "Unable to read".red(), path.red(), e);
+                imported.complete = false;
+                break;
+            }
+        };
+        imported.lines += 1;
+
+        match Logger::new(line.clone(), format) {
+            Ok(mut logger) => {
+                logger.set_source_file(path);
+                enrich(&mut logger);
+                log.push(logger);
+            }
+            Err(e) => {
+                unparsed.push(path, line.as_str(), &e);
+                imported.failed += 1;
+            }
+        }
+
+        if log.len() >= bulk_size || unparsed.len() >= bulk_size {
+            if bulk(server, &log, unparsed, stop) {
+                imported.sent += log.len();
+            } else {
+                imported.complete = false;
+            }
+            log.clear();
+            progress(&imported);
+        }
+    }
+    if !log.is_empty() || !unparsed.is_empty() {
+        if bulk(server, &log, unparsed, stop) {
+            imported.sent += log.len();
+        } else {
+            imported.complete = false;
+        }
+    }
+    progress(&imported);
+    println!("Imported {} of {} lines from {}", imported.sent, imported.lines, path);
+    imported
+}
+
+fn bulk(server : &Server, log : &[Logger], unparsed : &mut Unparsed, stop : &AtomicBool) -> bool {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            unparsed.flush(server).await;
+            log.is_empty() || server.bulk_retry(log, stop).await.is_empty()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use crate::mock::{Flavor, Mock};
+    use crate::server::is_es;
+
+    const COMBINED : &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+
+    fn temp_dir(name : &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust-logger-import-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// `count` lines, and one that can't be parsed after every third
+    fn lines(from : usize, count : usize) -> String {
+        let mut text = String::new();
+        for i in from..from + count {
+            text.push_str(format!("10.0.{}.{} - - [18/Oct/2022:08:41:00 +0000] \"GET / HTTP/1.1\" 200 12 \"-\" \"curl/7.0\"\r\n", i / 256, i % 256).as_str());
+            if i % 3 == 2 {
+                text.push_str("not a log line\n");
+            }
+        }
+        text
+    }
+
+    fn gzip(text : &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn server(mock : &Mock) -> Server {
+        let mut server = Server::new(format!("{}/logger", mock.url).as_str()).unwrap();
+        assert!(tokio::runtime::Runtime::new().unwrap().block_on(is_es(&mut server)));
+        server
+    }
+
+    fn import_one(server : &Server, path : &Path, unparsed : &mut Unparsed) -> Imported {
+        let format = LogFormat::new("combined", COMBINED).unwrap();
+        import_file(server, path.to_str().unwrap(), &format, 4, &mut |_| {}, unparsed, &AtomicBool::new(false))
+    }
+
+    #[test]
+    fn plain_and_gzip() {
+        let mock = Mock::start(Flavor::Elasticsearch7, None);
+        let server = server(&mock);
+        let dir = temp_dir("files");
+        let mut unparsed = Unparsed::new(dir.join("unparsed.ndjson").to_str().unwrap(), false);
+
+        let plain = dir.join("access.log");
+        fs::write(&plain, lines(0, 10)).unwrap();
+        assert_eq!(import_one(&server, &plain, &mut unparsed), Imported { lines: 13, sent: 10, failed: 3, complete: true });
+        assert_eq!(mock.documents("logger").len(), 10);
+        assert_eq!(fs::read_to_string(dir.join("unparsed.ndjson")).unwrap().lines().count(), 3);
+
+        // logrotate's delaycompress and a later append both end up as one file with several gzip members
+        let gzipped = dir.join("access.log.2.gz");
+        let mut members = gzip(lines(10, 5).as_str());
+        members.extend(gzip(lines(15, 5).as_str()));
+        fs::write(&gzipped, members).unwrap();
+        assert_eq!(import_one(&server, &gzipped, &mut unparsed), Imported { lines: 13, sent: 10, failed: 3, complete: true });
+        assert_eq!(mock.documents("logger").len(), 20);
+
+        // The same through import, which only says whether all of it went in
+        assert!(import(&server, &[(gzipped.to_str().unwrap().to_string(), LogFormat::new("combined", COMBINED).unwrap())], 4, &mut |_| {}, &mut unparsed, &AtomicBool::new(false)));
+    }
+
+    #[test]
+    fn progress_counts_the_compressed_bytes() {
+        let dir = temp_dir("progress");
+        let gzipped = dir.join("access.log.1.gz");
+        fs::write(&gzipped, gzip(lines(0, 100).as_str())).unwrap();
+        let (mut reader, read) = open_counted(gzipped.to_str().unwrap()).unwrap();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        assert_eq!(text, lines(0, 100));
+        assert_eq!(read.get(), fs::metadata(&gzipped).unwrap().len());
+    }
+
+    #[test]
+    fn unreadable_files_fail() {
+        let mock = Mock::start(Flavor::Elasticsearch7, None);
+        let server = server(&mock);
+        let dir = temp_dir("unreadable");
+        let mut unparsed = Unparsed::new(dir.join("unparsed.ndjson").to_str().unwrap(), false);
+        let format = || LogFormat::new("combined", COMBINED).unwrap();
+
+        let missing = dir.join("missing.log");
+        assert_eq!(import_one(&server, &missing, &mut unparsed), Imported::default());
+        let corrupt = dir.join("corrupt.log.gz");
+        let mut bytes = gzip(lines(0, 10).as_str());
+        bytes.truncate(bytes.len() / 2);
+        fs::write(&corrupt, bytes).unwrap();
+        assert!(!import_one(&server, &corrupt, &mut unparsed).complete);
+
+        // One bad file fails the whole import, but the rest are still read
+        let plain = dir.join("access.log");
+        fs::write(&plain, lines(0, 2)).unwrap();
+        let sources = vec![(missing.to_str().unwrap().to_string(), format()), (plain.to_str().unwrap().to_string(), format())];
+        assert!(!import(&server, &sources, 4, &mut |_| {}, &mut unparsed, &AtomicBool::new(false)));
+        assert_eq!(mock.documents("logger").len(), 2);
+    }
+}
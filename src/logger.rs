@@ -1,24 +1,79 @@
 use std::fs;
 use std::fs::File;
 use std::{io, fmt};
-use std::io::{BufRead, BufReader, Write};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
 use std::path::Path;
 use colored::Colorize;
-use serde_json;
-use regex::Regex;
-use reqwest::Response;
-use chrono::{DateTime, Local, Utc, TimeZone};
+use chrono::{DateTime, FixedOffset, Local, Utc, TimeZone};
 use sha1::{Sha1, Digest};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::IpAddr;
 
 
 use serde_derive::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 
 use crate::Server;
+use crate::format::{LogFormat, IP_VARIABLES, SIZE_VARIABLES, HOST_VARIABLES};
+use crate::proxy::{Cidr, real_client};
+use crate::useragent::{UserAgent, UserAgentParser};
+use crate::geoip::{Asn, Geo, GeoIp};
+use crate::import::open_log;
 
 
-///
+/// Format of the time field, indices from before had "epoch_second"
+const TIME_FORMAT : &str = "epoch_millis||strict_date_optional_time";
+
+/// How the mapping of an existing index compares to [Mapping]
+pub enum MappingStatus {
+    Valid,
+    /// The index is missing fields, which can be added with [Logger::update_mapping]
+    Missing(Vec<String>),
+    /// The index stores time in seconds, and has to be moved with [Logger::migrate_mapping]
+    OldTime,
+    Invalid
+}
+
+/// Why a line couldn't be turned into a document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line doesn't fit the log format at all
+    Mismatch,
+    /// None of the addresses is an ip, or all of them are trusted proxies
+    BadIp(String),
+    /// No time, or one that can't be read
+    BadDate(String),
+    /// Not a number, or too big for a status code
+    BadStatus(String),
+    BadSize(String),
+    NoRequest
+}
+impl ParseError {
+    /// Short name for the kind of error, to group them by
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ParseError::Mismatch => "regex_mismatch",
+            ParseError::BadIp(_) => "bad_ip",
+            ParseError::BadDate(_) => "bad_date",
+            ParseError::BadStatus(_) => "bad_status",
+            ParseError::BadSize(_) => "bad_size",
+            ParseError::NoRequest => "no_request"
+        }
+    }
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Mismatch => write!(f, "doesn't match the log format"),
+            ParseError::BadIp(ip) => write!(f, "no client ip in \"{}\"", ip),
+            ParseError::BadDate(date) => write!(f, "invalid date \"{}\"", date),
+            ParseError::BadStatus(status) => write!(f, "invalid status code \"{}\"", status),
+            ParseError::BadSize(size) => write!(f, "invalid size \"{}\"", size),
+            ParseError::NoRequest => write!(f, "no request")
+        }
+    }
+}
+
 /// When will nested structs be supported
 #[derive(Serialize, Deserialize)]
 struct Mapping{
@@ -33,13 +88,27 @@ struct Mappings{
 struct Properties{
     ip: Ip,
     alt_ip: Ip,
+    ip_chain: Ip,
     host: Text,
     request: Text,
     refer: Text,
     status_code: Short,
     size: Integer,
     user_agent: Text,
-    time: EpochS
+    time: Date,
+    timezone: Keyword,
+    method: Keyword,
+    path: Keyword,
+    query: Keyword,
+    http_version: Keyword,
+    request_time: Float,
+    upstream_response_time: Float,
+    upstream_addr: Keyword,
+    source_file: Keyword,
+    syslog_hostname: Keyword,
+    ua: UaObject,
+    geo: GeoObject,
+    asn: AsnObject
 }
 #[derive(Serialize, Deserialize)]
 struct Ip{
@@ -68,7 +137,54 @@ struct Integer{
     r#type: String
 }
 #[derive(Serialize, Deserialize)]
-struct EpochS {
+struct Float{
+    r#type: String
+}
+#[derive(Serialize, Deserialize)]
+struct Boolean{
+    r#type: String
+}
+#[derive(Serialize, Deserialize)]
+struct UaObject{
+    properties: UaProperties
+}
+#[derive(Serialize, Deserialize)]
+struct UaProperties{
+    browser: Keyword,
+    browser_version: Keyword,
+    os: Keyword,
+    device: Keyword,
+    is_bot: Boolean
+}
+#[derive(Serialize, Deserialize)]
+struct GeoObject{
+    properties: GeoProperties
+}
+#[derive(Serialize, Deserialize)]
+struct GeoProperties{
+    country_iso: Keyword,
+    city: Keyword,
+    location: GeoPoint
+}
+#[derive(Serialize, Deserialize)]
+struct GeoPoint{
+    r#type: String
+}
+#[derive(Serialize, Deserialize)]
+struct AsnObject{
+    properties: AsnProperties
+}
+#[derive(Serialize, Deserialize)]
+struct AsnProperties{
+    number: Long,
+    org: Keyword
+}
+#[derive(Serialize, Deserialize)]
+struct Long{
+    r#type: String
+}
+#[derive(Serialize, Deserialize)]
+struct Date {
     r#type: String,
     format: String
 }
@@ -84,6 +200,9 @@ impl Mapping{
                     alt_ip: Ip {
                         r#type: "ip".to_string()
                     },
+                    ip_chain: Ip {
+                        r#type: "ip".to_string()
+                    },
                     host: Text {
                         r#type: "text".to_string(),
                         fields: TextFields {
@@ -126,37 +245,179 @@ impl Mapping{
                             }
                         }
                     },
-                    time: EpochS {
+                    time: Date {
                         r#type: "date".to_string(),
-                        format: "epoch_second".to_string()
+                        format: TIME_FORMAT.to_string()
+                    },
+                    timezone: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256
+                    },
+                    method: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256
+                    },
+                    path: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 2048
+                    },
+                    query: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 2048
+                    },
+                    http_version: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256
+                    },
+                    request_time: Float {
+                        r#type: "float".to_string()
+                    },
+                    upstream_response_time: Float {
+                        r#type: "float".to_string()
+                    },
+                    upstream_addr: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256
+                    },
+                    source_file: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 1024
+                    },
+                    syslog_hostname: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256
+                    },
+                    ua: UaObject {
+                        properties: UaProperties {
+                            browser: Keyword {
+                                r#type: "keyword".to_string(),
+                                ignore_above: 256
+                            },
+                            browser_version: Keyword {
+                                r#type: "keyword".to_string(),
+                                ignore_above: 256
+                            },
+                            os: Keyword {
+                                r#type: "keyword".to_string(),
+                                ignore_above: 256
+                            },
+                            device: Keyword {
+                                r#type: "keyword".to_string(),
+                                ignore_above: 256
+                            },
+                            is_bot: Boolean {
+                                r#type: "boolean".to_string()
+                            }
+                        }
+                    },
+                    geo: GeoObject {
+                        properties: GeoProperties {
+                            country_iso: Keyword {
+                                r#type: "keyword".to_string(),
+                                ignore_above: 256
+                            },
+                            city: Keyword {
+                                r#type: "keyword".to_string(),
+                                ignore_above: 256
+                            },
+                            location: GeoPoint {
+                                r#type: "geo_point".to_string()
+                            }
+                        }
+                    },
+                    asn: AsnObject {
+                        properties: AsnProperties {
+                            // ASNs are unsigned 32 bit, too big for an integer
+                            number: Long {
+                                r#type: "long".to_string()
+                            },
+                            org: Keyword {
+                                r#type: "keyword".to_string(),
+                                ignore_above: 256
+                            }
+                        }
                     }
                 }
             }
         }
     }
 }
-///
-///
 
 /// This function expects a string like this
 /// ```
 /// 17/Sep/2022:23:39:19 +0200
 /// ```
-fn date_to_epoch(str : &str) -> u32{
-    let datetime = DateTime::parse_from_str(str, "%d/%b/%Y:%H:%M:%S %z");
-    if datetime.is_ok() == false {
-        return 0;
+fn date_to_datetime(str : &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_str(str, "%d/%b/%Y:%H:%M:%S %z").ok()
+}
+
+/// `$time_iso8601`
+/// ```
+/// 2022-09-17T23:39:19+02:00
+/// ```
+fn iso8601_to_datetime(str : &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(str).ok()
+}
+
+/// `$msec`, seconds with millisecond resolution, into milliseconds
+/// ```
+/// 1663450759.123 → 1663450759123
+/// ```
+fn msec_to_epoch(str : &str) -> Option<i64> {
+    let (seconds, fraction) = str.split_once('.').unwrap_or((str, "0"));
+    let seconds = seconds.parse::<i64>().ok()?;
+    let millis = format!("{:0<3}", fraction).get(..3)?.parse::<i64>().ok()?;
+    Some(seconds * 1000 + millis)
+}
+
+/// Splits the request line into method, path, query string and protocol
+/// ```
+/// GET /s/?search=test HTTP/1.1 → (GET, /s/, search=test, HTTP/1.1)
+/// ```
+/// Anything that doesn't look like a request line, like TLS handshakes sent to port 80, gives all None
+fn split_request(request : &str) -> (Option<String>, Option<String>, Option<String>, Option<String>) {
+    let parts : Vec<&str> = request.split(' ').collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        return (None, None, None, None);
     }
 
-    datetime.unwrap().timestamp() as u32
+    let method = parts[0];
+    if method.is_empty() || method.len() > 20 || !method.chars().all(|c| c.is_ascii_uppercase()) {
+        return (None, None, None, None);
+    }
+
+    // HTTP/0.9 requests don't have a protocol
+    let http_version = parts.get(2).map(|v| String::from(*v));
+    if http_version.as_ref().is_some_and(|v| !v.starts_with("HTTP/")) {
+        return (None, None, None, None);
+    }
+
+    let (path, query) = match parts[1].split_once('?') {
+        Some((path, query)) => (String::from(path), Some(String::from(query))),
+        None => (String::from(parts[1]), None)
+    };
+    (Some(String::from(method)), Some(path), query, http_version)
 }
 
+/// Splits nginx's list of upstream values, one per upstream that was tried
+/// ```
+/// 0.002, - : 0.031 → [0.002, None, 0.031]
+/// ```
+/// ", " separates servers in the same group, " : " separates groups after an internal redirect.
+/// Upstreams that didn't answer are written as "-" and kept as None, so the times and addresses stay in step
+fn split_upstream(value : &str) -> Vec<Option<String>> {
+    value.split(", ")
+        .flat_map(|group| group.split(" : "))
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| if v == "-" { None } else { Some(String::from(v)) })
+        .collect()
+}
 
 fn epoch_to_datetime(epoch : i64) -> String {
-    let naive = Local.timestamp(epoch, 0).naive_local();
+    let naive = Local.timestamp_millis(epoch).naive_local();
     let datetime = DateTime::<Utc>::from_local(naive, Utc);
-    let newdate = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
-    return newdate;
+    datetime.format("%Y-%m-%d %H:%M:%S%.3f").to_string()
 }
 
 fn dir_write_permission(path : String) -> bool { 
@@ -164,18 +425,17 @@ fn dir_write_permission(path : String) -> bool {
 
     // Try creating a file, and then deleting it right afterwards
     let file_res = File::create(file_path.clone());
-    if !file_res.is_ok() {
+    if file_res.is_err() {
         return false;
     }
 
     // Write a &str in the file (ignoring the result).
     let res = writeln!(&mut file_res.unwrap(), ":)");
-    if !res.is_ok() {
+    if res.is_err() {
         return false;
     }
-    res.unwrap();
 
-    fs::remove_file(file_path.clone()).expect(format!("The program crashed, you need to go delete {} manually", file_path).as_str());
+    fs::remove_file(file_path.clone()).unwrap_or_else(|_| panic!("The program crashed, you need to go delete {} manually", file_path));
     true
 }
 
@@ -184,30 +444,36 @@ fn dir_write_permission(path : String) -> bool {
 pub fn beautify_path(path : String) -> String{
     let mut new_path : String = String::new();
     let mut is_slash = false;
-    for (_, c) in path.chars().enumerate() {
+    for c in path.chars() {
         if c == '/' && is_slash{
             continue;
-        }else if c == '/'{
-            is_slash = true;
-        }else {
-            is_slash = false;
         }
+        is_slash = c == '/';
         new_path.push(c);
     }
-    if new_path.chars().last().unwrap() != '/' {
+    if !new_path.ends_with('/') {
         new_path.push('/');
     }
-    return new_path;
+    new_path
+}
+
+/// For stdin and pipes, where lines can't be sampled up front.
+/// The first format that can read the line wins, in the same order as the presets are tried
+pub fn detect_format(line : &str, formats : &[LogFormat]) -> Option<LogFormat> {
+    formats.iter()
+        .find(|format| Logger::new(String::from(line), format).is_ok())
+        .cloned()
 }
 
 /// Checks if Nginx log has valid format
-pub fn valid_log(loc : &str) -> bool {
-    if Path::new(loc).exists() == false {
-        return false;
+/// The first lines are tried with every format given, and the format matching the most lines is returned
+pub fn valid_log(loc : &str, formats : &[LogFormat]) -> Option<LogFormat> {
+    if !Path::new(loc).exists() {
+        return None;
     }
 
     if Path::new(loc).is_dir() {
-        return false;
+        return None;
     }
 
     // Check if able to read file
@@ -216,30 +482,43 @@ pub fn valid_log(loc : &str) -> bool {
         .write(false)
         .open(loc);
 
-    if res.is_ok() == false {
+    if res.is_err() {
         print!("No read permission");
-        return false;
+        return None;
     }
 
-    // Check the first 4 lines
-    let file = File::open(loc).unwrap();
-    let reader = BufReader::new(file);
+    // Check the first 11 lines
+    let reader = open_log(loc).unwrap();
+    let lines : Vec<String> = reader.lines().map_while(|line| line.ok()).take(11).collect();
+    let counter = lines.len();
 
-    let mut counter = 0;
-    let mut fails = 0;
-    for line in reader.lines() {
-        let result = Logger::new(line.unwrap().clone());
-        if counter > 10 {
-            break;
+    // Pick the format most lines agree with, the first one wins a tie
+    let mut format = formats.first()?.clone();
+    let mut fails = counter;
+    for candidate in formats {
+        let candidate_fails = lines.iter()
+            .filter(|line| Logger::new(line.to_string(), candidate).is_err())
+            .count();
+        if fails > candidate_fails {
+            fails = candidate_fails;
+            format = candidate.clone();
         }
-        if result.is_none() {
-            fails += 1;
+    }
+    if formats.len() > 1 && counter > 0 {
+        print!(" ({})", format.get_name());
+    }
+
+    // Regex mismatches can't say much, but JSON lines can tell what they're missing
+    if format.is_json() && fails > 0 {
+        let reason = lines.iter()
+            .find(|line| Logger::new(line.to_string(), &format).is_err())
+            .and_then(|line| format.missing(line));
+        if let Some(reason) = reason {
+            println!("  JSON lines are rejected: {}", reason);
         }
-        counter += 1;
     }
 
     let mut error = false;
-    let mut success_rate = 0.00;
     if counter == 0 {
         println!("  Found file, but it's empty: {}", loc);
         error = true;
@@ -247,11 +526,11 @@ pub fn valid_log(loc : &str) -> bool {
         println!("  Found file, but it contains less than 4 lines: {}", loc);
         error = true;
     }else{
-        success_rate = (counter - fails) as f64 / counter as f64;
-    }
-    if 0.75 > success_rate && success_rate != 0.00 {
-        println!("  Format errors in this file: ~{}%", (success_rate*100.0).round());
-        error = true;
+        let success_rate = (counter - fails) as f64 / counter as f64;
+        if 0.75 > success_rate {
+            println!("  Format errors in this file: ~{}%", ((1.0 - success_rate)*100.0).round());
+            error = true;
+        }
     }
 
     if error {
@@ -263,25 +542,25 @@ pub fn valid_log(loc : &str) -> bool {
         stdin.read_line(&mut user_input).expect("Expect input");
         user_input = String::from(user_input.trim());
         if user_input != "y" && user_input != "q" { // if n or something else
-            return false;
+            return None;
         } else if user_input == "q" {
             println!("Quitting...");
             std::process::exit(0);
         }
     }
 
-    true
+    Some(format)
 }
 
 /// Checks if Nginx log has valid format
 pub fn valid_archive(loc : &str) -> bool {
     let loc2 = beautify_path(loc.to_string());
-    if Path::new(loc2.as_str()).exists() == false {
+    if !Path::new(loc2.as_str()).exists() {
         print!(" The path does not exist");
         return false;
     }
 
-    if Path::new(loc2.as_str()).is_dir() == false {
+    if !Path::new(loc2.as_str()).is_dir() {
         print!(" The path is not a directory");
         return false;
     }
@@ -304,89 +583,154 @@ pub fn valid_archive(loc : &str) -> bool {
 }
 
 /// Server, containing protocol, hostname, port and db
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Logger{
     ip : String,
     alt_ip : Option<String>,
+    /// Every address the request went through, only set when there was a proxy
+    ip_chain : Option<Vec<String>>,
     host: Option<String>,
     request : String,
     refer : Option<String>,
     status_code : u16,
     size: u32,
     user_agent: Option<String>,
-    time: u32 // Who knows if this program lives to be 83 years old
+    /// Milliseconds since epoch
+    time: i64,
+    /// The offset the time was logged with, like +02:00
+    timezone: Option<String>,
+    method: Option<String>,
+    path: Option<String>,
+    query: Option<String>,
+    http_version: Option<String>,
+    /// Seconds, with millisecond resolution
+    request_time: Option<f64>,
+    /// One per upstream that was tried
+    /// None where the upstream didn't answer, so each time lines up with its address
+    upstream_response_time: Option<Vec<Option<f64>>>,
+    upstream_addr: Option<Vec<Option<String>>>,
+    /// The log file the line was read from
+    source_file: Option<String>,
+    /// The machine that sent the line, when it came in over syslog
+    syslog_hostname: Option<String>,
+    /// Browser, os and device from the user agent, filled in by [Logger::classify_user_agent]
+    ua: Option<UserAgent>,
+    /// Country, city and coordinates of the ip, filled in by [Logger::locate]
+    geo: Option<Geo>,
+    /// Network the ip belongs to, filled in by [Logger::locate]
+    asn: Option<Asn>,
+    /// Unknown keys from JSON logs, only kept when passthrough is enabled.
+    /// Not in the mapping, so they are stored in _source without being indexed
+    #[serde(flatten)]
+    extra: Map<String, Value>
 }
 impl Logger{
-    pub fn new(line : String) -> Option<Self> {
-        let re = Regex::new(r#"(.*) .* .* \[(.*)\] "(.*)" "(.*)" (\d+) (\d+) "(.*)" "(.*)""#).ok()?;
-        if re.is_match(line.as_str()) == false {
-            return None;
-        }
-
+    pub fn new(line : String, format : &LogFormat) -> Result<Self, ParseError> {
         // 127.0.0.1, 84.213.100.23 - - [20/Jul/2022:22:12:47 +0200] "knaben.ru" "GET /index.html HTTP/1.1" 403    153    "https://google.com/q=test" "Mozilla/5.0 (X11; Linux x86_64; rv:102.0) Gecko/20100101 Firefox/102.0"
-        // cap[1]                        cap[2]                       cap[3]      cap[4]                    cap[5] cap[6]  cap[7]                      cap[8]
-        let cap = re.captures(line.as_str())?;
+        // $http_x_forwarded_for         $time_local                  $host       $request                  $status $body_bytes_sent $http_referer      $http_user_agent
+        let fields = format.captures(line.as_str()).ok_or(ParseError::Mismatch)?;
+        let mut logger = Logger::from_fields(&fields, format.get_trusted_proxies())?;
 
-        // Getting ip(s)
-        let mut ip = &cap[1];
-        let mut alt_ip : Option<String> = None;
-        if ip.contains(",") {
-            let split: Vec<&str> = ip.split(",").collect();
-            ip = split[0].trim();
-            alt_ip = Some(String::from(split[1].trim()));
+        if format.get_passthrough() {
+            // Never let a passed through key overwrite one of our own fields
+            let own = serde_json::to_value(Logger::dummy_data()).unwrap();
+            logger.extra = format.unknown(line.as_str());
+            logger.extra.retain(|key, _| own.get(key).is_none());
         }
+        Ok(logger)
+    }
 
-        // verify ip addresses
-        if !ip.parse::<Ipv4Addr>().is_ok() && !ip.parse::<Ipv6Addr>().is_ok(){
-            println!("Not an ip :P");
-            return None;
+    /// Builds the document from nginx variables, `$remote_addr` → `fields["remote_addr"]`
+    /// `trusted` are the proxies whose X-Forwarded-For entries are believed
+    pub fn from_fields(fields : &HashMap<String, String>, trusted : &[Cidr]) -> Result<Self, ParseError> {
+        // Picks the first of the variables that is set, nginx writes "-" for empty values
+        let first = |variables : &[&str]| -> Option<String> {
+            variables.iter()
+                .filter_map(|v| fields.get(*v))
+                .find(|value| !value.is_empty() && value.as_str() != "-")
+                .cloned()
+        };
+
+        // Getting ip(s), the chain goes from the client to the address nginx got the request from
+        // Entries that aren't ip addresses, like "unknown", are dropped
+        let mut chain : Vec<IpAddr> = vec![];
+        if let Some(forwarded) = first(&IP_VARIABLES[..1]) {
+            chain.extend(forwarded.split(',').filter_map(|address| address.trim().parse::<IpAddr>().ok()));
         }
-        if !alt_ip.is_none() && !alt_ip.as_ref().unwrap().parse::<Ipv4Addr>().is_ok() && !alt_ip.as_ref().unwrap().parse::<Ipv6Addr>().is_ok(){
-            alt_ip = None;
+        if let Some(peer) = first(&IP_VARIABLES[1..]) {
+            if let Ok(peer) = peer.parse::<IpAddr>() {
+                chain.push(peer);
+            }
         }
 
-        // Getting the date
-        let time = date_to_epoch(&cap[2]);
-        if time == 0 {
-            return None;
-        }
+        // verify ip addresses
+        let client = real_client(&chain, trusted)
+            .ok_or_else(|| ParseError::BadIp(first(&IP_VARIABLES).unwrap_or_default()))?;
+        let ip = chain[client].to_string();
+        // The proxy that handed us the client
+        let alt_ip = chain.get(client + 1).map(|proxy| proxy.to_string());
+        let ip_chain = if chain.len() > 1 { Some(chain.iter().map(|ip| ip.to_string()).collect::<Vec<String>>()) } else { None };
+
+        // Getting the date, $msec is the only one with milliseconds, but the others have the offset
+        let datetime = first(&["time_local"]).and_then(|t| date_to_datetime(t.as_str()))
+            .or_else(|| first(&["time_iso8601"]).and_then(|t| iso8601_to_datetime(t.as_str())));
+        let timezone = datetime.map(|t| t.format("%:z").to_string());
+        let time = match first(&["msec"]).and_then(|t| msec_to_epoch(t.as_str())) {
+            Some(time) => time,
+            None => datetime
+                .ok_or_else(|| ParseError::BadDate(first(&["time_local", "time_iso8601", "msec"]).unwrap_or_default()))?
+                .timestamp_millis()
+        };
 
         // Getting the domain
-        let mut host : Option<String> = None;
-        if &cap[3] != "-" {
-            host = Some(String::from(&cap[3]));
-        }
+        let host = first(&HOST_VARIABLES);
 
-        let request = &cap[4];
-        let status_code_res = &cap[5].parse::<u16>();
-        if !status_code_res.is_ok() {
-            return None;
-        }
-        let status_code = status_code_res.clone().unwrap();
-        let size_res = &cap[6].parse::<u32>();
-        if !size_res.is_ok() {
-            return None;
-        }
-        let size = size_res.clone().unwrap();
-        let mut refer : Option<String> = None;
-        if &cap[7] != "-" {
-            refer = Some(String::from(&cap[7]));
-        }
-        let mut user_agent : Option<String> = None;
-        if &cap[8] != "-" {
-            user_agent = Some(String::from(&cap[8]));
-        }
+        let request = fields.get("request").ok_or(ParseError::NoRequest)?.clone();
+        let (method, path, query, http_version) = split_request(request.as_str());
+        let status = fields.get("status").cloned().unwrap_or_default();
+        let status_code = status.parse::<u16>().map_err(|_| ParseError::BadStatus(status))?;
+        // Apache writes "-" when nothing was sent
+        let size = match first(&SIZE_VARIABLES) {
+            Some(size) => size.parse::<u32>().map_err(|_| ParseError::BadSize(size))?,
+            None => 0
+        };
+        let refer = first(&["http_referer"]);
+        let user_agent = first(&["http_user_agent"]);
 
-        Some(Logger {
-            ip: String::from(ip),
+        // Timings are optional, a value that doesn't parse is left out instead of failing the line
+        let request_time = first(&["request_time"]).and_then(|v| v.parse::<f64>().ok());
+        let upstream_response_time = first(&["upstream_response_time"])
+            .map(|v| split_upstream(v.as_str()).into_iter().map(|t| t.and_then(|t| t.parse::<f64>().ok())).collect::<Vec<Option<f64>>>())
+            .filter(|v| v.iter().any(Option::is_some));
+        let upstream_addr = first(&["upstream_addr"])
+            .map(|v| split_upstream(v.as_str()))
+            .filter(|v| v.iter().any(Option::is_some));
+
+        Ok(Logger {
+            ip,
             host,
             alt_ip,
-            request: String::from(request),
+            ip_chain,
+            request,
             refer,
             status_code,
             size,
             user_agent,
-            time
+            time,
+            timezone,
+            method,
+            path,
+            query,
+            http_version,
+            request_time,
+            upstream_response_time,
+            upstream_addr,
+            source_file: None,
+            syslog_hostname: None,
+            ua: None,
+            geo: None,
+            asn: None,
+            extra: Map::new()
         })
     }
 
@@ -399,60 +743,188 @@ impl Logger{
         let ip = es.get("ip").unwrap().as_str().unwrap().to_string();
         let request = es.get("request").unwrap().as_str().unwrap().to_string();
         let status_code = es.get("status_code").unwrap().as_u64().unwrap() as u16;
-        let time = es.get("time").unwrap().as_u64().unwrap() as u32;
+        let time = es.get("time").unwrap().as_i64().unwrap();
         let size = es.get("size").unwrap().as_u64().unwrap() as u32;
+        let (method, path, query, http_version) = split_request(request.as_str());
 
         // Option field for alt_ip
         let mut alt_ip = None;
-        if !es.get("alt_ip").is_none() && !es.get("alt_ip").unwrap().is_null() {
+        if es.get("alt_ip").is_some() && !es.get("alt_ip").unwrap().is_null() {
             alt_ip = Some(es.get("alt_ip").unwrap().as_str().unwrap().to_string());
         }
 
+        // Option field for the proxy chain
+        let mut ip_chain = None;
+        if es.get("ip_chain").is_some() && !es.get("ip_chain").unwrap().is_null() {
+            let value = es.get("ip_chain").unwrap();
+            ip_chain = match value.as_array() {
+                Some(addresses) => Some(addresses.iter().filter_map(|a| a.as_str()).map(String::from).collect()),
+                None => value.as_str().map(|a| vec![String::from(a)])
+            };
+        }
+
         // Option field for host
         let mut host = None;
-        if !es.get("host").is_none() && !es.get("host").unwrap().is_null() {
+        if es.get("host").is_some() && !es.get("host").unwrap().is_null() {
             host = Some(es.get("host").unwrap().as_str().unwrap().to_string());
         }
 
         // Option field for user agent
         let mut refer = None;
-        if !es.get("refer").is_none() && !es.get("refer").unwrap().is_null() {
+        if es.get("refer").is_some() && !es.get("refer").unwrap().is_null() {
             refer = Some(es.get("refer").unwrap().as_str().unwrap().to_string());
         }
 
         // Option field for user agent
         let mut user_agent = None;
-        if !es.get("user_agent").is_none() && !es.get("user_agent").unwrap().is_null() {
+        if es.get("user_agent").is_some() && !es.get("user_agent").unwrap().is_null() {
             user_agent = Some(es.get("user_agent").unwrap().as_str().unwrap().to_string());
         }
 
+        // Option field for timezone
+        let mut timezone = None;
+        if es.get("timezone").is_some() && !es.get("timezone").unwrap().is_null() {
+            timezone = Some(es.get("timezone").unwrap().as_str().unwrap().to_string());
+        }
+
+        // Option fields for timings
+        let mut request_time = None;
+        if es.get("request_time").is_some() && !es.get("request_time").unwrap().is_null() {
+            request_time = es.get("request_time").unwrap().as_f64();
+        }
+
+        // Single values come back as a number instead of an array
+        let mut upstream_response_time = None;
+        if es.get("upstream_response_time").is_some() && !es.get("upstream_response_time").unwrap().is_null() {
+            let value = es.get("upstream_response_time").unwrap();
+            upstream_response_time = match value.as_array() {
+                Some(times) => Some(times.iter().map(|t| t.as_f64()).collect()),
+                None => value.as_f64().map(|t| vec![Some(t)])
+            };
+        }
+
+        let mut upstream_addr = None;
+        if es.get("upstream_addr").is_some() && !es.get("upstream_addr").unwrap().is_null() {
+            let value = es.get("upstream_addr").unwrap();
+            upstream_addr = match value.as_array() {
+                Some(addresses) => Some(addresses.iter().map(|a| a.as_str().map(String::from)).collect()),
+                None => value.as_str().map(|a| vec![Some(String::from(a))])
+            };
+        }
+
+        let mut source_file = None;
+        if es.get("source_file").is_some() && !es.get("source_file").unwrap().is_null() {
+            source_file = Some(String::from(es.get("source_file").unwrap().as_str().unwrap_or("")));
+        }
+
+        let mut syslog_hostname = None;
+        if es.get("syslog_hostname").is_some() && !es.get("syslog_hostname").unwrap().is_null() {
+            syslog_hostname = Some(String::from(es.get("syslog_hostname").unwrap().as_str().unwrap_or("")));
+        }
+
+        let mut ua = None;
+        if es.get("ua").is_some() && !es.get("ua").unwrap().is_null() {
+            ua = serde_json::from_value(es.get("ua").unwrap().clone()).ok();
+        }
+
+        let mut geo = None;
+        if es.get("geo").is_some() && !es.get("geo").unwrap().is_null() {
+            geo = serde_json::from_value(es.get("geo").unwrap().clone()).ok();
+        }
+
+        let mut asn = None;
+        if es.get("asn").is_some() && !es.get("asn").unwrap().is_null() {
+            asn = serde_json::from_value(es.get("asn").unwrap().clone()).ok();
+        }
+
         // Delete this
         Some(Logger {
             ip,
             alt_ip,
+            ip_chain,
             host,
             request,
             refer,
             status_code,
             size,
             user_agent,
-            time
+            time,
+            timezone,
+            method,
+            path,
+            query,
+            http_version,
+            request_time,
+            upstream_response_time,
+            upstream_addr,
+            source_file,
+            syslog_hostname,
+            ua,
+            geo,
+            asn,
+            extra: Map::new()
         })
     }
 
+    pub fn set_source_file(&mut self, path : &str) {
+        self.source_file = Some(String::from(path));
+    }
+
+    pub fn set_syslog_hostname(&mut self, hostname : &str) {
+        self.syslog_hostname = Some(String::from(hostname));
+    }
+
+    /// Adds browser, os, device and whether it's a bot, from the user agent
+    pub fn classify_user_agent(&mut self, parser : &mut UserAgentParser) {
+        if let Some(user_agent) = self.user_agent.as_ref() {
+            self.ua = Some(parser.parse(user_agent.as_str()));
+        }
+    }
+
+    /// Adds the location and network of the ip. When ip isn't in the database, like a private address,
+    /// the addresses further out in the chain are tried, but never one of our own proxies
+    pub fn locate(&mut self, geoip : &mut GeoIp, trusted : &[Cidr]) {
+        // From the client outwards, everything between it and nginx is trusted
+        let ips : Vec<IpAddr> = match &self.ip_chain {
+            Some(chain) => chain.iter().rev()
+                .filter_map(|ip| ip.parse::<IpAddr>().ok())
+                .filter(|ip| !trusted.iter().any(|cidr| cidr.contains(ip)))
+                .collect(),
+            None => self.ip.parse::<IpAddr>().into_iter().collect()
+        };
+
+        self.geo = ips.iter().find_map(|ip| geoip.city(ip));
+        self.asn = ips.iter().find_map(|ip| geoip.asn(ip));
+    }
+
     /// Use the dummy data for testing,
     /// use the new() function for actual new logging
     pub fn dummy_data() -> Self {
         Logger {
             ip: "127.0.0.1".to_string(),
             alt_ip: None,
+            ip_chain: None,
             host: None,
             request: "".to_string(),
             refer: None,
             status_code: 200,
             size: 420,
             user_agent: None,
-            time: 0
+            time: 0,
+            timezone: None,
+            method: None,
+            path: None,
+            query: None,
+            http_version: None,
+            request_time: None,
+            upstream_response_time: None,
+            upstream_addr: None,
+            source_file: None,
+            syslog_hostname: None,
+            ua: None,
+            geo: None,
+            asn: None,
+            extra: Map::new()
         }
     }
 
@@ -472,31 +944,33 @@ impl Logger{
             .clone();
 
         for elm in keys.iter() {
-            if keys2.contains_key(elm.0) == false {
+            if !keys2.contains_key(elm.0) {
                 panic!("{} Does not exist in struct", elm.0)
             }
         }
 
         for elm in keys2.iter() {
-            if keys.contains_key(elm.0) == false {
+            if !keys.contains_key(elm.0) {
                 panic!("{} Does not exist in mapping", elm.0)
             }
         }
         true
     }
 
-    pub async fn valid_mapping(db: String, res : Response) -> bool{
-        if Logger::double_check_mapping() == false {
-            return false;
+    /// `j` is the answer to `GET /<db>`
+    pub fn valid_mapping(db: String, j : Value) -> MappingStatus{
+        if !Logger::double_check_mapping() {
+            return MappingStatus::Invalid;
         }
-        let j : Value = res.json().await.expect("Expected valid JSON");
-        if j[db.clone()]["mappings"]["properties"].is_null() {
-            return false;
+        // When db is an alias, the response is keyed by the index behind it
+        let index = if j[db.clone()].is_null() { j.as_object().and_then(|o| o.values().next()).cloned().unwrap_or_default() } else { j[db].clone() };
+        if index["mappings"]["properties"].is_null() {
+            return MappingStatus::Invalid;
         }
-        if j[db.clone()]["mappings"]["properties"].as_object().is_some() == false {
-            return false;
+        if index["mappings"]["properties"].as_object().is_none() {
+            return MappingStatus::Invalid;
         }
-        let keys = j[db]["mappings"]["properties"]
+        let keys = index["mappings"]["properties"]
             .as_object()
             .unwrap();
         let mapping : Mapping = Mapping::new();
@@ -507,35 +981,55 @@ impl Logger{
             .clone();
 
         for elm in keys.keys() {
-            if keys2.contains_key(elm) == false{
+            if !keys2.contains_key(elm) {
                 print!(" Should not contain: {}", elm);
-                return false;
+                return MappingStatus::Invalid;
             }
         }
-        for elm in keys2.keys() {
-            if keys.contains_key(elm) == false{
-                print!(" DB does not contain: {}", elm);
-                return false;
+        // The format of a field can't be changed on an existing index. Without time at all it's just missing
+        if let Some(time) = keys.get("time") {
+            if time["format"].as_str() != Some(TIME_FORMAT) {
+                print!(" Time is stored as: {}", time["format"]);
+                return MappingStatus::OldTime;
             }
         }
-        true
+        // Fields added in newer versions can be put on the existing index
+        let missing : Vec<String> = keys2.keys()
+            .filter(|elm| !keys.contains_key(*elm))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            print!(" DB does not contain: {}", missing.join(", "));
+            return MappingStatus::Missing(missing);
+        }
+        MappingStatus::Valid
+    }
+
+    /// The mapping every index is created with
+    pub fn mapping() -> Value {
+        serde_json::to_value(Mapping::new()).unwrap()
     }
 
     pub async fn create_mapping(server : Server) -> Option<bool> {
-        if Logger::double_check_mapping() == false {
+        if !Logger::double_check_mapping() {
             return None;
         }
         let mapping : Mapping = Mapping::new();
-        let request = reqwest::Client::new()
+        let request = server.http()
             .put(server.get_url())
             .json(&mapping)
+            .headers(server.headers(false))
             .send()
             .await.ok()?
             .text()
             .await.ok()?;
 
         let res : Value = serde_json::from_str(request.as_str()).unwrap();
-        if res["acknowledged"].is_boolean() == false || res["acknowledged"].as_bool().unwrap() == false {
+        // A bulk got there first and created it without the mapping, or another rust-logger did
+        if res["error"]["type"] == "resource_already_exists_exception" {
+            return Logger::update_mapping(server).await;
+        }
+        if !res["acknowledged"].is_boolean() || !res["acknowledged"].as_bool().unwrap() {
             print!("[X] {}", request);
             return None;
         }
@@ -544,74 +1038,250 @@ impl Logger{
         Some(true)
     }
 
-    /// This function will generate the id for the document
-    /// It's sha1(epoch + ip)
-    pub fn get_id(&self) -> String {
-        let mut hasher = Sha1::new();
-        let raw = format!("{}{}", self.time, self.ip);
-        hasher.update(raw.into_bytes());
-        format!("{:X}", hasher.finalize())
-    }
-}
-impl Clone for Logger{
-    fn clone(&self) -> Logger {
-        let alt_ip : Option<String>;
-        if self.alt_ip.is_none() {
-            alt_ip = None;
-        }else{
-            alt_ip = Option::from(self.alt_ip.as_ref().unwrap().clone())
+    /// Adds the fields missing from an existing index, fields already there are left as is
+    pub async fn update_mapping(server : Server) -> Option<bool> {
+        if !Logger::double_check_mapping() {
+            return None;
         }
+        let mapping : Mapping = Mapping::new();
+        let request = server.http()
+            .put(format!("{}/_mapping", server.get_url()))
+            .json(&mapping.mappings)
+            .headers(server.headers(false))
+            .send()
+            .await.ok()?
+            .text()
+            .await.ok()?;
 
-        let host : Option<String>;
-        if self.alt_ip.is_none() {
-            host = None;
-        }else{
-            host = Option::from(self.host.as_ref().unwrap().clone())
+        let res : Value = serde_json::from_str(request.as_str()).unwrap();
+        if !res["acknowledged"].is_boolean() || !res["acknowledged"].as_bool().unwrap() {
+            print!("[X] {}", request);
+            return None;
         }
 
-        let refer : Option<String>;
-        if self.refer.is_none() {
-            refer = None;
-        }else{
-            refer = Option::from(self.refer.as_ref().unwrap().clone())
+        print!("[ ] Updated: {}", server);
+        Some(true)
+    }
+
+    /// Moves an index with time in seconds over to a new index with milliseconds.
+    /// The old index is replaced with an alias of the same name, so the server url doesn't have to change
+    pub async fn migrate_mapping(server : Server) -> Option<bool> {
+        if !Logger::double_check_mapping() {
+            return None;
         }
+        let mapping : Mapping = Mapping::new();
+        let client = server.http();
+        let db = server.get_db();
+        let new_db = format!("{}-millis", db);
 
-        let user_agent : Option<String>;
-        if self.user_agent.is_none() {
-            user_agent = None;
-        }else{
-            user_agent = Option::from(self.user_agent.as_ref().unwrap().clone())
+        // Create the new index
+        let res : Value = client
+            .put(format!("{}/{}", server.get_host(), new_db))
+            .json(&mapping)
+            .headers(server.headers(false))
+            .send()
+            .await.ok()?
+            .json()
+            .await.ok()?;
+        if !res["acknowledged"].as_bool().unwrap_or(false) {
+            print!("[X] {}", res);
+            return None;
         }
 
-        Logger {
-            ip: self.ip.clone(),
-            alt_ip,
-            host,
-            request: self.request.clone(),
-            refer,
-            status_code: self.status_code.clone(),
-            size: self.size.clone(),
-            user_agent,
-            time: self.time.clone()
+        // Copy every document, converting time on the way
+        println!("  Reindexing {} into {}, this can take a while", db, new_db);
+        let res : Value = client
+            .post(format!("{}/_reindex?wait_for_completion=true&refresh=true", server.get_host()))
+            .json(&serde_json::json!({
+                "source": {
+                    "index": db
+                },
+                "dest": {
+                    "index": new_db
+                },
+                "script": {
+                    "lang": "painless",
+                    "source": "ctx._source.time = ctx._source.time * 1000L"
+                }
+            }))
+            .headers(server.headers(false))
+            .send()
+            .await.ok()?
+            .json()
+            .await.ok()?;
+        if res["failures"].as_array().map(|f| !f.is_empty()).unwrap_or(true) {
+            print!("[X] {}", res);
+            return None;
+        }
+        println!("  Reindexed {} documents", res["total"]);
+
+        // Swap the old index for an alias
+        let res : Value = client
+            .delete(server.get_url())
+            .send()
+            .await.ok()?
+            .json()
+            .await.ok()?;
+        if !res["acknowledged"].as_bool().unwrap_or(false) {
+            print!("[X] {}", res);
+            return None;
         }
+        let res : Value = client
+            .post(format!("{}/_aliases", server.get_host()))
+            .json(&serde_json::json!({
+                "actions": [
+                    { "add": { "index": new_db, "alias": db } }
+                ]
+            }))
+            .headers(server.headers(false))
+            .send()
+            .await.ok()?
+            .json()
+            .await.ok()?;
+        if !res["acknowledged"].as_bool().unwrap_or(false) {
+            print!("[X] {}", res);
+            return None;
+        }
+
+        print!("[ ] Migrated: {} → {}", server, new_db);
+        Some(true)
     }
-}
 
+    /// Milliseconds since epoch
+    pub fn get_time(&self) -> i64 {
+        self.time
+    }
 
+    /// This function will generate the id for the document
+    /// It's sha1(epoch + ip + source_file + request), several files share a bulk,
+    /// so the same client on two vhosts in the same second are still two documents
+    pub fn get_id(&self) -> String {
+        let mut hasher = Sha1::new();
+        let raw = format!("{}{}{}{}", self.time, self.ip, self.source_file.as_deref().unwrap_or(""), self.request);
+        hasher.update(raw.into_bytes());
+        format!("{:X}", hasher.finalize())
+    }
+}
 impl fmt::Display for Logger{
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let ip = &self.ip;
-        let alt_ip : String = if self.alt_ip.is_none() { "None".to_string() } else { self.alt_ip.as_ref().unwrap().to_string() };
-        let host : String = if self.host.is_none() { "None".to_string() } else { self.host.as_ref().unwrap().to_string() };
+        let alt_ip : &str = self.alt_ip.as_deref().unwrap_or("None");
+        let host : &str = self.host.as_deref().unwrap_or("None");
         let size : String = self.size.to_string();
         let status_code : String = self.status_code.to_string();
-        let request : String = self.request.clone();
-        let refer : String = if self.refer.is_none() { "None".to_string() } else { self.refer.as_ref().unwrap().to_string() };
-        let user_agent : String = if self.user_agent.is_none() { "None".to_string() } else { self.user_agent.as_ref().unwrap().to_string() };
-        let time = epoch_to_datetime(self.time as i64);
+        let request : &str = self.request.as_str();
+        let refer : &str = self.refer.as_deref().unwrap_or("None");
+        let user_agent : &str = self.user_agent.as_deref().unwrap_or("None");
+        let time = epoch_to_datetime(self.time);
+        let request_time : String = self.request_time.map(|t| format!("{:.3}", t)).unwrap_or_else(|| "None".to_string());
+        let upstream_response_time : String = match &self.upstream_response_time {
+            Some(times) => times.iter().map(|t| t.map(|t| format!("{:.3}", t)).unwrap_or_else(|| "-".to_string())).collect::<Vec<String>>().join(", "),
+            None => "None".to_string()
+        };
+        let upstream_addr : String = match &self.upstream_addr {
+            Some(addresses) => addresses.iter().map(|a| a.as_deref().unwrap_or("-")).collect::<Vec<&str>>().join(", "),
+            None => "None".to_string()
+        };
 
-        let line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", time, ip, alt_ip, host, status_code, request, refer, user_agent, size);
+        let line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", time, ip, alt_ip, host, status_code, request, refer, user_agent, size, request_time, upstream_response_time, upstream_addr);
         write!(f, "{}", line)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Like the answer to `GET /logger`, with `change` made to the properties
+    fn index(change : impl Fn(&mut Map<String, Value>)) -> Value {
+        let mut mapping = Logger::mapping();
+        change(mapping["mappings"]["properties"].as_object_mut().unwrap());
+        json!({ "logger": mapping })
+    }
+
+    #[test]
+    fn mapping_status() {
+        assert!(matches!(Logger::valid_mapping(String::from("logger"), index(|_| {})), MappingStatus::Valid));
+        // Behind an alias it's keyed by the index
+        let behind = json!({ "logger-2022.10.18": index(|_| {})["logger"] });
+        assert!(matches!(Logger::valid_mapping(String::from("logger"), behind), MappingStatus::Valid));
+
+        let missing = Logger::valid_mapping(String::from("logger"), index(|properties| { properties.remove("time"); }));
+        assert!(matches!(missing, MappingStatus::Missing(fields) if fields == vec!["time"]));
+        let seconds = index(|properties| { properties.insert(String::from("time"), json!({"type": "date", "format": "epoch_second"})); });
+        assert!(matches!(Logger::valid_mapping(String::from("logger"), seconds), MappingStatus::OldTime));
+        let foreign = index(|properties| { properties.insert(String::from("message"), json!({"type": "text"})); });
+        assert!(matches!(Logger::valid_mapping(String::from("logger"), foreign), MappingStatus::Invalid));
+        assert!(matches!(Logger::valid_mapping(String::from("logger"), json!({ "logger": {"mappings": {}} })), MappingStatus::Invalid));
+    }
+
+    #[test]
+    fn every_parse_error_has_its_reason() {
+        let combined = LogFormat::new("combined", crate::format::COMBINED).unwrap();
+        let json = LogFormat::json();
+        let line = |ip : &str, time : &str| format!(r#"{} - - [{}] "GET / HTTP/1.1" 200 12 "-" "curl/7.0""#, ip, time);
+        let json_line = |status : &str, size : &str, request : &str| format!(
+            r#"{{"remote_addr": "10.0.0.1", "time_local": "18/Oct/2022:08:41:00 +0000", "request": "{}", "status": "{}", "body_bytes_sent": "{}"}}"#,
+            request, status, size
+        );
+
+        let cases = [
+            (Logger::new(String::from("garbage"), &combined), ParseError::Mismatch, "regex_mismatch"),
+            (Logger::new(line("unknown", "18/Oct/2022:08:41:00 +0000"), &combined), ParseError::BadIp(String::from("unknown")), "bad_ip"),
+            (Logger::new(line("10.0.0.1", "32/Oct/2022:08:41:00 +0000"), &combined), ParseError::BadDate(String::from("32/Oct/2022:08:41:00 +0000")), "bad_date"),
+            (Logger::new(json_line("abc", "12", "GET / HTTP/1.1"), &json), ParseError::BadStatus(String::from("abc")), "bad_status"),
+            (Logger::new(json_line("200", "twelve", "GET / HTTP/1.1"), &json), ParseError::BadSize(String::from("twelve")), "bad_size"),
+            (Logger::new(json_line("200", "12", "GET / HTTP/1.1").replace(r#""request": "GET / HTTP/1.1", "#, ""), &json), ParseError::NoRequest, "no_request")
+        ];
+        for (result, error, reason) in cases {
+            let e = result.err().unwrap_or_else(|| panic!("{} was parsed", reason));
+            assert_eq!(e, error);
+            assert_eq!(e.reason(), reason);
+        }
+    }
+
+    #[test]
+    fn request_line() {
+        let some = |v : &str| Some(String::from(v));
+        assert_eq!(split_request("GET /s/?search=test HTTP/1.1"), (some("GET"), some("/s/"), some("search=test"), some("HTTP/1.1")));
+        assert_eq!(split_request("GET /a?b?c HTTP/2.0"), (some("GET"), some("/a"), some("b?c"), some("HTTP/2.0")));
+        // HTTP/0.9
+        assert_eq!(split_request("GET /"), (some("GET"), some("/"), None, None));
+
+        for request in ["", "-", "\\x16\\x03\\x01", "get / HTTP/1.1", "GET / HTTP/1.1 extra", "GET / SPDY/3", "GET"] {
+            assert_eq!(split_request(request), (None, None, None, None), "{}", request);
+        }
+    }
+
+    #[test]
+    fn upstreams_stay_in_step() {
+        let some = |v : &str| Some(String::from(v));
+        assert_eq!(split_upstream("0.002"), vec![some("0.002")]);
+        assert_eq!(split_upstream("0.002, 0.010 : 0.031"), vec![some("0.002"), some("0.010"), some("0.031")]);
+        assert_eq!(split_upstream("- : 0.031"), vec![None, some("0.031")]);
+        assert_eq!(split_upstream("10.0.0.1:80, 10.0.0.2:80 : 10.0.0.3:80"), vec![some("10.0.0.1:80"), some("10.0.0.2:80"), some("10.0.0.3:80")]);
+        assert_eq!(split_upstream(""), Vec::<Option<String>>::new());
+
+        let json = LogFormat::json();
+        let line = |times : &str, addresses : &str| format!(
+            r#"{{"remote_addr": "10.0.0.1", "time_local": "18/Oct/2022:08:41:00 +0000", "request": "GET / HTTP/1.1", "status": "200", "body_bytes_sent": "12", "upstream_response_time": "{}", "upstream_addr": "{}"}}"#,
+            times, addresses
+        );
+
+        // The first server didn't answer, the second did
+        let logger = Logger::new(line("-, 0.010 : 0.031", "10.0.0.1:80, 10.0.0.2:80 : unix:/run/app.sock"), &json).unwrap();
+        assert_eq!(logger.upstream_response_time, Some(vec![None, Some(0.010), Some(0.031)]));
+        assert_eq!(logger.upstream_addr, Some(vec![some("10.0.0.1:80"), some("10.0.0.2:80"), some("unix:/run/app.sock")]));
+        assert!(logger.to_string().ends_with("\t-, 0.010, 0.031\t10.0.0.1:80, 10.0.0.2:80, unix:/run/app.sock"));
+
+        // Positions survive the trip through Elasticsearch
+        let back = Logger::from_es(serde_json::to_value(&logger).unwrap()).unwrap();
+        assert_eq!(back.upstream_response_time, logger.upstream_response_time);
+        assert_eq!(back.upstream_addr, logger.upstream_addr);
+
+        let nothing = Logger::new(line("-", "-"), &json).unwrap();
+        assert_eq!(nothing.upstream_response_time, None);
+        assert_eq!(nothing.upstream_addr, None);
+    }
+}
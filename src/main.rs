@@ -1,41 +1,106 @@
 use std::{env, thread, sync::Arc, sync::Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, RecvTimeoutError};
+use std::collections::HashMap;
+use std::thread::JoinHandle;
 use std::io::{stdout, Write};
 use std::path::Path;
+use std::time::{Duration, Instant};
 use chrono::{Local, NaiveTime};
 use colored::Colorize;
-use logwatcher::{LogWatcher, LogWatcherAction};
+use tokio::signal::unix::{signal, SignalKind};
 
 // headers
 pub mod server;
 mod logger;
+mod format;
+mod config;
+mod proxy;
+mod useragent;
+mod geoip;
+mod state;
+mod tail;
+mod import;
+mod syslog;
+mod spool;
+mod deadletter;
+mod auth;
+mod version;
+mod template;
+#[cfg(test)]
+mod mock;
 
 use server::Server;
-use crate::logger::{Logger, valid_log, valid_archive, beautify_path};
+use crate::logger::{Logger, ParseError, valid_log, valid_archive, beautify_path};
+use crate::format::LogFormat;
+use crate::config::Config;
+use crate::proxy::{Cidr, default_trusted};
+use crate::useragent::UserAgentParser;
+use crate::geoip::GeoIp;
+use crate::state::{Checkpoint, State, DEFAULT_STATE_FILE};
+use crate::tail::{Line, Source, Tailer};
+use crate::spool::{Spool, DEFAULT_SPOOL_DIR, DEFAULT_SPOOL_MAX_MB};
+use crate::auth::Auth;
+use crate::template::Template;
+use crate::deadletter::{Unparsed, DEFAULT_DEAD_LETTER_FILE, DEFAULT_UNPARSED_FILE};
 use crate::server::*;
 
 fn epoch_days_ago(days : i64) -> i64{
     let time = Local::now() + chrono::Duration::days(-days);
-    let epoch = time.date().and_time(NaiveTime::from_num_seconds_from_midnight(0,0)).unwrap().timestamp();
-    return epoch;
+    time.date().and_time(NaiveTime::from_num_seconds_from_midnight(0,0)).unwrap().timestamp()
 }
 
+/// Set on SIGTERM (systemd stopping the service) or SIGINT (Ctrl-C).
+/// A second signal exits right away, without waiting for anything
+fn stop_on_signal() -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop2 = Arc::clone(&stop);
+    thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut terminate = signal(SignalKind::terminate()).unwrap();
+                let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = interrupt.recv() => {}
+                }
+                println!("{}", "Stopping, send the signal again to quit without saving anything".yellow());
+                stop2.store(true, Ordering::SeqCst);
+
+                // Exits like it was killed by the signal
+                tokio::select! {
+                    _ = terminate.recv() => std::process::exit(128 + 15),
+                    _ = interrupt.recv() => std::process::exit(128 + 2)
+                }
+            });
+    });
+    stop
+}
 
+
+#[allow(non_snake_case)]
 fn main() {
 
-    #[allow(non_snake_case)]
     // Default values
     let BULK_SIZE = 500;
+    let mut FLUSH_INTERVAL = 5; // Seconds
     //let ARCHIVE_TIME = 30; // Days
     let ARCHIVE_TIME = 30; // Days
+    let GLOB_INTERVAL = 10; // Seconds
     let mut archive_enable = true;
+    let mut import_mode = false;
+    let mut config = Config::default();
 
     let args: Vec<String> = env::args().collect();
 
     // Possible default servers
     // First priority from top to bottom
     let mut servers : Vec<Server> = vec![
-        Server::new("http://127.0.0.1:9200/logger"),
-        Server::new("http://192.168.1.137:9200/logger")
+        Server::new("http://127.0.0.1:9200/logger").unwrap(),
+        Server::new("http://192.168.1.137:9200/logger").unwrap()
     ];
 
     // Possible default locations
@@ -56,18 +121,73 @@ fn main() {
     let mut new_locations: Vec<&str> = vec![];
     let mut new_servers: Vec<Server> = vec![];
     let mut new_archiving: Vec<&str> = vec![];
-    for arg in &args[1..]{
-        if Path::new(arg).is_dir() {
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        if arg == "-f" || arg == "--config" {
+            let path = iter.next().map(|p| p.as_str()).unwrap_or("");
+            config = match Config::load(path) {
+                Ok(config) => config,
+                Err(e) => {
+                    println!("{}", e.red());
+                    std::process::exit(1);
+                }
+            };
+        }
+        else if arg == "-i" || arg == "--import" {
+            import_mode = true;
+        }
+        else if Path::new(arg).is_dir() {
             new_archiving.push(arg);
         }
         else if Path::new(arg).exists() {
             new_locations.push(arg);
         }
         else if server::is_url(String::from(arg)){
-            new_servers.push(Server::new(arg));
+            match Server::new(arg) {
+                Ok(server) => new_servers.push(server),
+                Err(e) => {
+                    println!("{}", e.red());
+                    std::process::exit(1);
+                }
+            }
+        }
+        else if tail::is_glob(arg) || arg == "-" {
+            new_locations.push(arg);
         }
     }
 
+    // Logging in the same way to every server
+    let auth = match Auth::from_config(&config) {
+        Ok(auth) => auth,
+        Err(e) => {
+            println!("{} {}", "Invalid Elasticsearch credentials:".red(), e.red());
+            std::process::exit(1);
+        }
+    };
+    let template = match Template::from_config(&config) {
+        Ok(template) => template,
+        Err(e) => {
+            println!("{} {}", "Invalid index template settings:".red(), e.red());
+            std::process::exit(1);
+        }
+    };
+    for server in new_servers.iter_mut().chain(servers.iter_mut()) {
+        if let Err(e) = server.set_auth(&auth) {
+            println!("{} {}: {}", "Unable to connect to".red(), server.to_string().red(), e);
+            std::process::exit(1);
+        }
+        if let Some(pattern) = &config.index_pattern {
+            if let Err(e) = server.set_index_pattern(pattern.as_str()) {
+                println!("{}", e.red());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Importing the default locations from the start is rarely what's wanted, so only the given files are
+    if import_mode && !new_locations.is_empty() {
+        locations.clear();
+    }
     new_locations.reverse();
     locations.reverse();
     locations.extend(new_locations);
@@ -83,23 +203,75 @@ fn main() {
     archiving.extend(new_archiving);
     archiving.reverse();
 
+    // Compiling the log format, or trying every preset if none is given
+    let mut formats : Vec<LogFormat> = match &config.log_format {
+        Some(log_format) => match LogFormat::from_config(log_format.as_str()) {
+            Ok(format) => vec![format],
+            Err(e) => {
+                println!("{} {}", "Invalid log_format:".red(), e.red());
+                std::process::exit(1);
+            }
+        },
+        None => LogFormat::presets()
+    };
+    let trusted_proxies : Vec<Cidr> = match &config.trusted_proxies {
+        Some(proxies) => proxies.iter().map(|proxy| match Cidr::new(proxy) {
+            Ok(cidr) => cidr,
+            Err(e) => {
+                println!("{} {}", "Invalid trusted_proxies:".red(), e.red());
+                std::process::exit(1);
+            }
+        }).collect(),
+        None => default_trusted()
+    };
+    for format in formats.iter_mut() {
+        format.set_passthrough(config.json_passthrough);
+        format.set_trusted_proxies(trusted_proxies.clone());
+    }
+
+    // Optional GeoIP databases
+    let mut geoip : Option<GeoIp> = None;
+    if config.geoip_city.is_some() || config.geoip_asn.is_some() {
+        geoip = match GeoIp::new(config.geoip_city.as_deref(), config.geoip_asn.as_deref()) {
+            Ok(geoip) => Some(geoip),
+            Err(e) => {
+                println!("{} {}", "Invalid GeoIP database:".red(), e.red());
+                std::process::exit(1);
+            }
+        };
+    }
 
-    // Choosing a file path
-    let mut location : String = String::from("");
-    println!("Checking file location ({}: {}, {}: {}, {}: {}): ", "✓".green(), "chosen".green(), "-".yellow(), "skip".yellow(), "X".red(), "Not found".red());
-    for loc in &locations {
+
+    // Choosing the file paths, every valid one is followed, each with its own format.
+    // stdin and named pipes can't be sampled, so their format is found from the first line that comes in
+    let mut sources : Vec<Source> = vec![];
+    println!("Checking file locations ({}: {}, {}: {}, {}: {}): ", "✓".green(), "chosen".green(), "-".yellow(), "skip".yellow(), "X".red(), "Not found".red());
+    for loc in tail::expand(&locations) {
         print!("[ ] {} ...", loc);
         stdout().flush().unwrap();
-        if !location.is_empty() && Path::new(loc).exists() {
+        if sources.iter().any(|source| source.path.as_deref() == Some(loc.as_str())) {
+            print!("{}", "\r[-]\n".yellow());
+        }else if !import_mode && import::is_gzip(loc.as_str()) {
+            print!("{}", " (Compressed, only read with --import)".yellow());
             print!("{}", "\r[-]\n".yellow());
-        }else if valid_log(loc) {
+        }else if tail::is_stream(loc.as_str()) {
+            if import_mode {
+                print!("{}", " (Only files can be imported)".yellow());
+                print!("{}", "\r[-]\n".yellow());
+            } else {
+                print!("{}", "\r[✓]\n".green());
+                let format = if formats.len() == 1 { formats.first().cloned() } else { None };
+                sources.push(Source::new(if loc == "-" { "stdin" } else { loc.as_str() }, Some(loc.clone()), format));
+            }
+        }else if let Some(detected) = valid_log(loc.as_str(), &formats) {
             print!("{}", "\r[✓]\n".green());
-            location = String::from(*loc);
+            sources.push(Source::new(loc.as_str(), Some(loc.clone()), Some(detected)));
         }else{
             print!("{}", "\r[X]\n".red());
         }
     }
-    if location.is_empty() {
+    let syslog_enable = !import_mode && (config.syslog_udp.is_some() || config.syslog_tcp.is_some());
+    if sources.is_empty() && !syslog_enable {
         println!("{}", "No log file found to log data from".red());
         std::process::exit(1);
     }
@@ -113,15 +285,15 @@ fn main() {
         .build()
         .unwrap()
         .block_on(async {
-            for ser in servers {
+            for mut ser in servers {
                 print!("[ ] {} ...", ser);
                 stdout().flush().unwrap();
                 if _server.is_some() {
                     print!("{}", " (Not bothering checking)".yellow());
                     print!("{}", "\r[-]\n".yellow());
-                } else if db_exists(ser.clone()).await {
+                } else if db_exists(&mut ser).await {
                     print!("{}", "\r[✓]\n".green());
-                    _server = Some(ser.clone());
+                    _server = Some(ser);
                 } else {
                     print!("{}", "\r[X]\n".red());
                 }
@@ -129,11 +301,54 @@ fn main() {
             println!();
         });
 
-    if _server.is_some() == false{
+    if _server.is_none() {
         println!("{}", "No server found to log data to".red());
         std::process::exit(1);
     }
-    let server = _server.unwrap();
+    let mut server = _server.unwrap();
+    server.set_dead_letter(config.dead_letter_file.as_deref().unwrap_or(DEFAULT_DEAD_LETTER_FILE));
+    if config.sniff {
+        server.set_sniff(true);
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                server.sniff().await;
+            });
+        println!("Sending to {}", server);
+    }
+    if let Some(template) = &template {
+        let installed = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(template.install(&server));
+        match installed {
+            Ok(installed) => server.set_template(installed),
+            Err(e) => println!("{} {}", e.red(), "the mapping is put on each index instead".red())
+        }
+    }
+
+    // User agent, GeoIP etc. added to every document
+    let mut ua_parser = UserAgentParser::new();
+    let mut enrich = |logger : &mut Logger| {
+        logger.classify_user_agent(&mut ua_parser);
+        if let Some(geoip) = geoip.as_mut() {
+            logger.locate(geoip, &trusted_proxies);
+        }
+    };
+
+    // Reading every file once from start to end, instead of following them
+    if import_mode {
+        let stop = stop_on_signal();
+        let files : Vec<(String, LogFormat)> = sources.into_iter()
+            .filter_map(|source| Some((source.path?, source.format?)))
+            .collect();
+        let mut unparsed = Unparsed::new(config.unparsed_file.as_deref().unwrap_or(DEFAULT_UNPARSED_FILE), config.index_unparsed);
+        let success = import::import(&server, &files, BULK_SIZE, &mut enrich, &mut unparsed, &stop);
+        std::process::exit(if success { 0 } else { 1 });
+    }
 
     // Choosing an archiving path
     let mut archive: String = String::from("");
@@ -159,25 +374,137 @@ fn main() {
 
 
     // And then for the actual logging
-    let mut log_watcher = LogWatcher::register(location).unwrap();
+    let mut state = State::load(config.state_file.as_deref().unwrap_or(DEFAULT_STATE_FILE));
+    let mut unparsed = Unparsed::new(config.unparsed_file.as_deref().unwrap_or(DEFAULT_UNPARSED_FILE), config.index_unparsed);
+    let mut spool = Spool::open(config.spool_dir.as_deref().unwrap_or(DEFAULT_SPOOL_DIR), config.spool_max_mb.unwrap_or(DEFAULT_SPOOL_MAX_MB));
+    let stop = stop_on_signal();
+    // Every file is read in its own thread, and the lines end up here
+    let (sender, lines) = sync_channel::<Line>(BULK_SIZE * 10);
+    for (source, Source { path, .. }) in sources.iter().enumerate() {
+        let path = match path {
+            Some(path) => path,
+            None => continue
+        };
+        if tail::is_stream(path.as_str()) {
+            tail::stream(path.as_str(), source, sender.clone(), Arc::clone(&stop));
+            continue;
+        }
+        let tailer = match Tailer::open(path.as_str(), state.get(path.as_str())) {
+            Ok(tailer) => tailer,
+            Err(e) => {
+                println!("{} {}", "Unable to open log file:".red(), e.to_string().red());
+                std::process::exit(1);
+            }
+        };
+        tail::follow(tailer, source, sender.clone(), Arc::clone(&stop));
+    }
+    // Listening for nginx sending its logs with syslog, the format is found for each sender
+    if syslog_enable {
+        for (protocol, address) in [("udp", &config.syslog_udp), ("tcp", &config.syslog_tcp)] {
+            let address = match address {
+                Some(address) => address.as_str(),
+                None => continue
+            };
+            let format = if formats.len() == 1 { formats.first().cloned() } else { None };
+            sources.push(Source::new(format!("syslog {}://{}", protocol, address).as_str(), None, format));
+            let listening = if protocol == "udp" {
+                syslog::listen_udp(address, sources.len() - 1, sender.clone(), Arc::clone(&stop))
+            } else {
+                syslog::listen_tcp(address, sources.len() - 1, sender.clone(), Arc::clone(&stop))
+            };
+            if let Err(e) = listening {
+                println!("{} {}://{}: {}", "Unable to listen on".red(), protocol.red(), address.red(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    // Glob patterns are looked at again every GLOB_INTERVAL, so the sender is kept to follow what they match later
+    let globs : Vec<&str> = locations.iter().copied().filter(|location| tail::is_glob(location)).collect();
+    let mut last_glob = Instant::now();
+    let sender = if globs.is_empty() {
+        drop(sender);
+        None
+    } else {
+        Some(sender)
+    };
+    if let Some(interval) = config.flush_interval {
+        FLUSH_INTERVAL = interval;
+    }
     let mut counter = 0;
     let mut log : Vec<Logger> = vec![];
+    // When the oldest document still waiting to be sent was read
+    let mut oldest : Option<Instant> = None;
+    // Only saved once the lines up to here have been indexed
+    let mut checkpoints : HashMap<String, Checkpoint> = HashMap::new();
     let run = Arc::new(Mutex::new(false));
+    let mut archiver : Option<JoinHandle<bool>> = None;
 
     // Get time epoch since midnight 30 days ago 
     let mut epoch = epoch_days_ago(ARCHIVE_TIME);
 
-    log_watcher.watch(&mut move |line: String| {
-        let logger : Option<Logger> = Logger::new(line.clone());
-        if logger.is_none() {
-            println!("Failed? {}", line);
-            return LogWatcherAction::None;
+    while !stop.load(Ordering::SeqCst) {
+        match lines.recv_timeout(Duration::from_secs(1)) {
+            Ok(line) => {
+                let source = &mut sources[line.source];
+                if let (Some(path), Some(checkpoint)) = (source.path.as_ref(), line.checkpoint) {
+                    checkpoints.insert(path.clone(), checkpoint);
+                }
+                let logger = match source.format_for(&line, &formats) {
+                    Some(format) => Logger::new(line.text.clone(), format),
+                    None => Err(ParseError::Mismatch)
+                };
+                match logger {
+                    Ok(mut logger) => {
+                        if let Some(path) = source.path.as_ref().filter(|path| *path != "-") {
+                            logger.set_source_file(path.as_str());
+                        }
+                        if let Some(hostname) = line.hostname.as_ref() {
+                            logger.set_syslog_hostname(hostname.as_str());
+                        }
+                        enrich(&mut logger);
+                        log.push(logger);
+                        counter += 1;
+                    }
+                    // Counted when they're flushed, printing each one would flood the output with bad traffic
+                    Err(e) => {
+                        unparsed.push(source.name.as_str(), line.text.as_str(), &e);
+                    }
+                }
+                if oldest.is_none() {
+                    oldest = Some(Instant::now());
+                }
+            }
+            // Nothing new for a second. The flush below still runs, so a quiet log doesn't keep documents waiting
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break
         }
 
-        log.push(logger.unwrap());
-        counter += 1;
+        // Like the log of a vhost that was added, every line in it is new.
+        // It's usually too short to sample, so the format is found from the first line like for a pipe
+        if let Some(sender) = sender.as_ref().filter(|_| last_glob.elapsed() >= Duration::from_secs(GLOB_INTERVAL)) {
+            last_glob = Instant::now();
+            for path in tail::new_matches(&globs, &sources) {
+                if import::is_gzip(path.as_str()) || tail::is_stream(path.as_str()) {
+                    continue;
+                }
+                let tailer = match state.get(path.as_str()) {
+                    Some(checkpoint) => Tailer::open(path.as_str(), Some(checkpoint)),
+                    None => Tailer::open_new(path.as_str())
+                };
+                match tailer {
+                    Ok(tailer) => {
+                        println!("{} {}", "Following new file".green(), path.green());
+                        let format = if formats.len() == 1 { formats.first().cloned() } else { None };
+                        sources.push(Source::new(path.as_str(), Some(path.clone()), format));
+                        tail::follow(tailer, sources.len() - 1, sender.clone(), Arc::clone(&stop));
+                    }
+                    Err(e) => println!("{} {}: {}", "Unable to open".red(), path.red(), e)
+                }
+            }
+        }
 
-        if counter >= BULK_SIZE {
+        let waited_too_long = oldest.map(|oldest| oldest.elapsed() >= Duration::from_secs(FLUSH_INTERVAL)).unwrap_or(false);
+        if counter >= BULK_SIZE || waited_too_long || spool.is_due() {
             // Check if new day and archiving is not happening
             let run1 = Arc::clone(&run);
             let mut running = run1.lock().unwrap();
@@ -200,12 +527,14 @@ fn main() {
                     // Setting up variables to be sent to thread
                     let server2 = server.clone();
                     let run2 = Arc::clone(&run);
+                    let stop2 = Arc::clone(&stop);
                     let archive = archive.clone();
-                    thread::spawn(move || {
-                        server2.archive(archive, epoch);
+                    archiver = Some(thread::spawn(move || {
+                        let done = server2.archive(archive, epoch, &stop2);
                         let mut running = run2.lock().unwrap();
                         *running = false;
-                    });
+                        done
+                    }));
                 }else{
                     println!("Nothing to archive. No documents older than {} days.", ARCHIVE_TIME);
                     *running = false;
@@ -215,19 +544,90 @@ fn main() {
             //    println!("Already running, can't do this now");
             //}
 
-            tokio::runtime::Builder::new_multi_thread()
+            let kept = tokio::runtime::Builder::new_multi_thread()
                 .enable_all()
                 .build()
                 .unwrap()
                 .block_on(async {
-                    // Send the bulk
-                    server.bulk(&log).await;
+                    unparsed.flush(&server).await;
+                    // Whatever was spooled goes first, so the documents arrive in the order they were logged
+                    let caught_up = spool.replay(&server).await;
+                    if log.is_empty() {
+                        return vec![];
+                    }
+                    // Send the bulk, and keep what's left on disk while Elasticsearch is away
+                    let left = if caught_up { server.bulk_retry(&log, &stop).await } else { log.clone() };
+                    let saved = left.is_empty() || match spool.push(&left) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            println!("{}", e.red());
+                            false
+                        }
+                    };
+                    if !saved {
+                        // Neither sent nor on disk, so they stay in memory and the checkpoints aren't saved past them
+                        return left;
+                    }
+                    if let Err(e) = state.save(&checkpoints) {
+                        println!("{}", e.red());
+                    }
+                    vec![]
                 });
 
+            // Tried again after another flush_interval, or once a full bulk has come in on top of them
             counter = 0;
-            log.clear();
+            oldest = if kept.is_empty() { None } else { Some(Instant::now()) };
+            log = kept;
         }
+    }
 
-        LogWatcherAction::None
-    });
+    // Stopping, so send what's left and wait for archiving to get to a safe point
+    let mut code = 0;
+    if !unparsed.is_empty() {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                unparsed.flush(&server).await
+            });
+    }
+    if !log.is_empty() {
+        println!("Sending the last {} documents", log.len());
+        let left = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                if spool.is_empty() { server.bulk(&log).await } else { log.clone() }
+            });
+        if !left.is_empty() {
+            match spool.push(&left) {
+                Ok(()) => println!("{}", "Unable to send the last documents, they will be sent from the spool on the next start".yellow()),
+                Err(e) => {
+                    // Not saving the checkpoint, so they're read again on the next start
+                    println!("{}", e.red());
+                    println!("{}", "Unable to send the last documents, they will be read again on the next start".red());
+                    code = 1;
+                }
+            }
+        }
+    }
+    if code == 0 {
+        if let Err(e) = state.save(&checkpoints) {
+            println!("{}", e.red());
+            code = 1;
+        }
+    }
+
+    if let Some(archiver) = archiver {
+        if !archiver.is_finished() {
+            println!("Waiting for archiving to stop");
+        }
+        if archiver.join().is_err() {
+            println!("{}", "Archiving crashed".red());
+            code = 1;
+        }
+    }
+    std::process::exit(code);
 }
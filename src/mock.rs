@@ -0,0 +1,463 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use serde_json::{json, Value};
+
+
+/// Which server to act like, each answers with what the real one sends back
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flavor {
+    Elasticsearch6,
+    Elasticsearch7,
+    Elasticsearch8,
+    /// Set to claim it's Elasticsearch 7.10.2, which OpenSearch 1 can do for older clients
+    OpenSearch1Compat,
+    OpenSearch2
+}
+
+/// A request the mock got, header names are lowercase
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: String
+}
+
+/// A stand-in for a single node, for the requests rust-logger makes.
+/// Like the real ones, Elasticsearch 8 checks the compatibility headers, and OpenSearch turns them away
+pub struct Mock {
+    pub url: String,
+    flavor: Flavor,
+    /// Authorization header every request needs, like Elasticsearch 8 with security on
+    auth: Option<String>,
+    requests: Mutex<Vec<Request>>,
+    /// Each index with the body it was created with
+    indices: Mutex<HashMap<String, Value>>,
+    /// Every document with the index it's in
+    documents: Mutex<Vec<(String, Value)>>,
+    templates: Mutex<HashMap<String, Value>>,
+    /// ILM or ISM policies, with the sequence number ISM wants to see when one is replaced
+    policies: Mutex<HashMap<String, (Value, u64)>>,
+    /// Like `http.max_content_length`, bigger requests get a 413
+    max_content_length: Mutex<Option<usize>>,
+    /// How many items to leave off the end of bulk responses
+    missing_items: Mutex<usize>,
+    /// Statuses the next bulk items are answered with, in order, before going back to 201
+    item_statuses: Mutex<Vec<u16>>,
+    /// Hangs up on every request without answering, like a node that went away
+    down: Mutex<bool>
+}
+impl Mock {
+    pub fn start(flavor : Flavor, auth : Option<&str>) -> Arc<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mock = Arc::new(Mock {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            flavor,
+            auth: auth.map(String::from),
+            requests: Mutex::new(vec![]),
+            indices: Mutex::new(HashMap::new()),
+            documents: Mutex::new(vec![]),
+            templates: Mutex::new(HashMap::new()),
+            policies: Mutex::new(HashMap::new()),
+            max_content_length: Mutex::new(None),
+            missing_items: Mutex::new(0),
+            item_statuses: Mutex::new(vec![]),
+            down: Mutex::new(false)
+        });
+        let server = mock.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let server = server.clone();
+                thread::spawn(move || server.serve(stream));
+            }
+        });
+        mock
+    }
+
+    pub fn set_max_content_length(&self, bytes : usize) {
+        *self.max_content_length.lock().unwrap() = Some(bytes);
+    }
+
+    /// Leaves the last `count` items out of bulk responses
+    pub fn leave_out_items(&self, count : usize) {
+        *self.missing_items.lock().unwrap() = count;
+    }
+
+    /// Answers the next bulk items with these statuses, one each, and doesn't keep the documents that get an error
+    pub fn answer_items(&self, statuses : &[u16]) {
+        *self.item_statuses.lock().unwrap() = statuses.to_vec();
+    }
+
+    pub fn set_down(&self, down : bool) {
+        *self.down.lock().unwrap() = down;
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The last request to a path ending with `suffix`
+    pub fn last(&self, suffix : &str) -> Option<Request> {
+        self.requests().into_iter().rev().find(|request| request.path.ends_with(suffix))
+    }
+
+    pub fn indices(&self) -> Vec<String> {
+        let mut names : Vec<String> = self.indices.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The body `index` was created with
+    pub fn index(&self, index : &str) -> Option<Value> {
+        self.indices.lock().unwrap().get(index).cloned()
+    }
+
+    pub fn template(&self, name : &str) -> Option<Value> {
+        self.templates.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn remove_template(&self, name : &str) {
+        self.templates.lock().unwrap().remove(name);
+    }
+
+    pub fn policy(&self, name : &str) -> Option<Value> {
+        self.policies.lock().unwrap().get(name).map(|(policy, _)| policy.clone())
+    }
+
+    /// Documents in `index`
+    pub fn documents(&self, index : &str) -> Vec<Value> {
+        self.documents.lock().unwrap().iter().filter(|(name, _)| name == index).map(|(_, document)| document.clone()).collect()
+    }
+
+    /// Indices behind a comma separated list of indices and aliases
+    fn resolve(&self, target : &str) -> Vec<String> {
+        let indices = self.indices.lock().unwrap();
+        let mut names = vec![];
+        for name in target.split(',') {
+            if indices.contains_key(name) {
+                names.push(String::from(name));
+            } else {
+                names.extend(indices.iter().filter(|(_, body)| !body["aliases"][name].is_null()).map(|(index, _)| index.clone()));
+            }
+        }
+        names.sort();
+        names
+    }
+
+    /// Creates an index from what was sent, on top of the index template with the highest priority that matches its name
+    fn create(&self, name : &str, body : Value) {
+        let templates = self.templates.lock().unwrap();
+        let matches = |pattern : &Value| pattern.as_str().map(|pattern| match pattern.split_once('*') {
+            Some((prefix, suffix)) => name.starts_with(prefix) && name.ends_with(suffix) && name.len() >= prefix.len() + suffix.len(),
+            None => name == pattern
+        }).unwrap_or(false);
+        let template = templates.values()
+            .filter(|template| template["index_patterns"].as_array().map(|patterns| patterns.iter().any(matches)).unwrap_or(false))
+            .max_by_key(|template| template["priority"].as_u64().unwrap_or(0));
+
+        let mut index = template.map(|template| template["template"].clone()).unwrap_or_else(|| json!({}));
+        if let Some(body) = body.as_object() {
+            for (key, value) in body {
+                index[key.as_str()] = value.clone();
+            }
+        }
+        self.indices.lock().unwrap().insert(String::from(name), index);
+    }
+
+    /// Adds fields to the indices, like Elasticsearch a field can't change its type
+    fn put_mapping(&self, target : &str, request : &Request) -> (u16, Value) {
+        let mapping : Value = serde_json::from_str(request.body.as_str()).unwrap_or_default();
+        let added = mapping["properties"].as_object().cloned().unwrap_or_default();
+        let names = self.resolve(target);
+        if names.is_empty() {
+            return (404, json!({"error": {"type": "index_not_found_exception"}, "status": 404}));
+        }
+        let mut indices = self.indices.lock().unwrap();
+        for name in names {
+            let body = indices.get_mut(&name).unwrap();
+            let properties = &mut body["mappings"]["properties"];
+            for (field, definition) in added.iter() {
+                if !properties[field].is_null() && properties[field]["type"] != definition["type"] {
+                    return (400, json!({"error": {"type": "illegal_argument_exception", "reason": format!("mapper [{}] cannot be changed", field)}, "status": 400}));
+                }
+                properties[field] = definition.clone();
+            }
+        }
+        (200, json!({"acknowledged": true}))
+    }
+
+    fn put_policy(&self, name : &str, request : &Request) -> (u16, Value) {
+        let body : Value = serde_json::from_str(request.body.as_str()).unwrap_or_default();
+        let mut policies = self.policies.lock().unwrap();
+        let seq_no = policies.get(name).map(|(_, seq_no)| *seq_no);
+        if let Some(seq_no) = seq_no {
+            let sent = request.path.split("if_seq_no=").nth(1).and_then(|rest| rest.split('&').next()).and_then(|seq_no| seq_no.parse::<u64>().ok());
+            if self.flavor == Flavor::OpenSearch2 && sent != Some(seq_no) {
+                return (409, json!({"error": {"type": "version_conflict_engine_exception"}, "status": 409}));
+            }
+        }
+        policies.insert(String::from(name), (body, seq_no.map(|seq_no| seq_no + 1).unwrap_or(0)));
+        (200, json!({"acknowledged": true, "_id": name}))
+    }
+
+    /// Keeps answering on the same connection, reqwest reuses them
+    fn serve(&self, stream : TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = line.split_whitespace();
+            let method = String::from(parts.next().unwrap_or(""));
+            let path = String::from(parts.next().unwrap_or("/"));
+
+            let mut headers = HashMap::new();
+            loop {
+                let mut header = String::new();
+                if reader.read_line(&mut header).unwrap_or(0) == 0 || header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    headers.insert(name.trim().to_lowercase(), String::from(value.trim()));
+                }
+            }
+            let length = headers.get("content-length").and_then(|length| length.parse::<usize>().ok()).unwrap_or(0);
+            let mut body = vec![0; length];
+            if reader.read_exact(&mut body).is_err() {
+                return;
+            }
+
+            let request = Request { method, path, headers, body: String::from_utf8_lossy(&body).to_string() };
+            self.requests.lock().unwrap().push(request.clone());
+            if *self.down.lock().unwrap() {
+                return;
+            }
+            let (status, response) = self.answer(&request);
+            let text = match &response {
+                Value::Null => String::new(),
+                response => response.to_string()
+            };
+            let head = format!("HTTP/1.1 {} Mock\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n", status, text.len());
+            // HEAD only gets the headers
+            let out = if request.method == "HEAD" { head } else { format!("{}{}", head, text) };
+            if stream.write_all(out.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn answer(&self, request : &Request) -> (u16, Value) {
+        if let Some(auth) = &self.auth {
+            if request.headers.get("authorization") != Some(auth) {
+                return (401, json!({
+                    "error": {
+                        "type": "security_exception",
+                        "reason": "missing authentication credentials for REST request [/]"
+                    },
+                    "status": 401
+                }));
+            }
+        }
+        if let Some(error) = self.check_headers(request) {
+            return error;
+        }
+        if self.max_content_length.lock().unwrap().map(|max| request.body.len() > max).unwrap_or(false) {
+            return (413, Value::Null);
+        }
+
+        let path = request.path.split('?').next().unwrap_or("/");
+        let segments : Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("HEAD", []) => (200, Value::Null),
+            ("GET", []) => (200, self.root()),
+            ("GET", ["_nodes", ..]) => (200, self.nodes()),
+            ("PUT", [index]) => {
+                let body : Value = serde_json::from_str(request.body.as_str()).unwrap_or_default();
+                if self.indices.lock().unwrap().contains_key(*index) {
+                    return (400, json!({"error": {"type": "resource_already_exists_exception", "reason": format!("index [{}] already exists", index)}, "status": 400}));
+                }
+                self.create(index, body);
+                (200, json!({"acknowledged": true, "shards_acknowledged": true, "index": index}))
+            }
+            ("GET", [target]) => {
+                let names = self.resolve(target);
+                if names.is_empty() {
+                    return (404, json!({"error": {"type": "index_not_found_exception"}, "status": 404}));
+                }
+                let indices = self.indices.lock().unwrap();
+                (200, Value::Object(names.into_iter().map(|name| (name.clone(), indices[&name].clone())).collect()))
+            }
+            ("DELETE", [target]) => {
+                let names = self.resolve(target);
+                self.indices.lock().unwrap().retain(|name, _| !names.contains(name));
+                self.documents.lock().unwrap().retain(|(name, _)| !names.contains(name));
+                (200, json!({"acknowledged": true}))
+            }
+            ("PUT", [target, "_mapping"]) => self.put_mapping(target, request),
+            ("PUT", ["_index_template", name]) => {
+                let body : Value = serde_json::from_str(request.body.as_str()).unwrap_or_default();
+                self.templates.lock().unwrap().insert(String::from(*name), body);
+                (200, json!({"acknowledged": true}))
+            }
+            ("GET", ["_index_template", name]) => match self.template(name) {
+                Some(template) => (200, json!({"index_templates": [{"name": name, "index_template": template}]})),
+                None => (404, json!({"error": {"type": "resource_not_found_exception"}, "status": 404}))
+            },
+            ("PUT", ["_ilm", "policy", name]) if self.flavor != Flavor::OpenSearch2 => self.put_policy(name, request),
+            ("GET", ["_ilm", "policy", name]) if self.flavor != Flavor::OpenSearch2 => match self.policy(name) {
+                Some(policy) => (200, json!({ *name: {"version": 1, "policy": policy["policy"]} })),
+                None => (404, json!({"error": {"type": "resource_not_found_exception"}, "status": 404}))
+            },
+            ("PUT", ["_plugins", "_ism", "policies", name]) if self.flavor == Flavor::OpenSearch2 => self.put_policy(name, request),
+            ("GET", ["_plugins", "_ism", "policies", name]) if self.flavor == Flavor::OpenSearch2 => match self.policies.lock().unwrap().get(*name) {
+                Some((policy, seq_no)) => (200, json!({"_id": name, "_seq_no": seq_no, "_primary_term": 1, "policy": policy["policy"]})),
+                None => (404, json!({"error": {"type": "status_exception"}, "status": 404}))
+            },
+            ("POST", ["_bulk"]) => self.bulk(None, request.body.as_str()),
+            ("POST", [index, "_bulk"]) => self.bulk(Some(index), request.body.as_str()),
+            ("POST", [target, "_count"]) | ("GET", [target, "_count"]) => {
+                let names = self.resolve(target);
+                let count = self.documents.lock().unwrap().iter().filter(|(name, _)| names.contains(name)).count();
+                (200, json!({"count": count}))
+            }
+            ("POST", [target, "_search"]) | ("GET", [target, "_search"]) => (200, self.newest(target)),
+            _ => (400, json!({"error": format!("no handler for {} {}", request.method, path), "status": 400}))
+        }
+    }
+
+    /// What the real servers do with the compatibility media types
+    fn check_headers(&self, request : &Request) -> Option<(u16, Value)> {
+        let content_type = request.headers.get("content-type").cloned().unwrap_or_default();
+        let accept = request.headers.get("accept").cloned().unwrap_or_default();
+        let compatible = |value : &str| value.split("compatible-with=").nth(1).map(|version| String::from(version.trim()));
+        match self.flavor {
+            Flavor::OpenSearch1Compat | Flavor::OpenSearch2 => {
+                if content_type.contains("vnd.elasticsearch") && !request.body.is_empty() {
+                    return Some((406, json!({"error": format!("Content-Type header [{}] is not supported", content_type), "status": 406})));
+                }
+                None
+            }
+            Flavor::Elasticsearch8 => {
+                let with_body = !request.body.is_empty();
+                let (sent, wanted) = (compatible(content_type.as_str()), compatible(accept.as_str()));
+                if with_body && sent != wanted {
+                    return Some((400, json!({
+                        "error": "A compatible version is required on both Content-Type and Accept headers if either one has requested a compatible version and the compatible versions must match.",
+                        "status": 400
+                    })));
+                }
+                match wanted.as_deref() {
+                    None | Some("7") | Some("8") => None,
+                    Some(version) => Some((400, json!({"error": format!("Unsupported compatible-with={}", version), "status": 400})))
+                }
+            }
+            _ => None
+        }
+    }
+
+    fn root(&self) -> Value {
+        match self.flavor {
+            Flavor::Elasticsearch6 => json!({
+                "name": "node-1",
+                "cluster_name": "elasticsearch",
+                "cluster_uuid": "k4wWz8nYQ4GfKCOZPkh9Tw",
+                "version": {"number": "6.8.23", "build_flavor": "default", "lucene_version": "7.7.3"},
+                "tagline": "You Know, for Search"
+            }),
+            Flavor::Elasticsearch7 => json!({
+                "name": "node-1",
+                "cluster_name": "elasticsearch",
+                "cluster_uuid": "k4wWz8nYQ4GfKCOZPkh9Tw",
+                "version": {"number": "7.17.9", "build_flavor": "default", "lucene_version": "8.11.1"},
+                "tagline": "You Know, for Search"
+            }),
+            Flavor::Elasticsearch8 => json!({
+                "name": "node-1",
+                "cluster_name": "docker-cluster",
+                "cluster_uuid": "o2qk0yXcTkmXgKyKH5RzHg",
+                "version": {"number": "8.11.1", "build_flavor": "default", "lucene_version": "9.8.0", "minimum_wire_compatibility_version": "7.17.0"},
+                "tagline": "You Know, for Search"
+            }),
+            Flavor::OpenSearch1Compat => json!({
+                "name": "opensearch-node1",
+                "cluster_name": "opensearch-cluster",
+                "cluster_uuid": "Qm2vDbtLRdWd2IN2Ol7wrw",
+                "version": {"number": "7.10.2", "build_flavor": "oss", "lucene_version": "8.10.1"},
+                "tagline": "The OpenSearch Project: https://opensearch.org/"
+            }),
+            Flavor::OpenSearch2 => json!({
+                "name": "opensearch-node1",
+                "cluster_name": "opensearch-cluster",
+                "cluster_uuid": "Qm2vDbtLRdWd2IN2Ol7wrw",
+                "version": {"distribution": "opensearch", "number": "2.11.0", "lucene_version": "9.7.0"},
+                "tagline": "The OpenSearch Project: https://opensearch.org/"
+            })
+        }
+    }
+
+    /// This node, plus one that only manages the cluster
+    fn nodes(&self) -> Value {
+        let address = self.url.trim_start_matches("http://");
+        let manager = match self.flavor {
+            Flavor::OpenSearch2 => "cluster_manager",
+            _ => "master"
+        };
+        json!({
+            "nodes": {
+                "a": {"roles": ["data", "ingest", manager], "http": {"publish_address": format!("localhost/{}", address)}},
+                "b": {"roles": [manager], "http": {"publish_address": "127.0.0.1:1"}}
+            }
+        })
+    }
+
+    /// Only what's used to find expired indices, the newest `time` in each index
+    fn newest(&self, target : &str) -> Value {
+        let names = self.resolve(target);
+        let documents = self.documents.lock().unwrap();
+        let buckets : Vec<Value> = names.iter()
+            .map(|name| {
+                let times = documents.iter().filter(|(index, _)| index == name).filter_map(|(_, document)| document["time"].as_i64());
+                json!({"key": name, "doc_count": times.clone().count(), "newest": {"value": times.max()}})
+            })
+            .collect();
+        json!({"hits": {"total": {"value": 0, "relation": "eq"}, "hits": []}, "aggregations": {"indices": {"buckets": buckets}}})
+    }
+
+    /// Like Elasticsearch, a missing index is created on the fly, without a mapping
+    fn bulk(&self, index : Option<&str>, body : &str) -> (u16, Value) {
+        let lines : Vec<Value> = body.lines().filter_map(|line| serde_json::from_str(line).ok()).collect();
+        let mut items = vec![];
+        for pair in lines.chunks(2) {
+            let action = &pair[0]["index"];
+            if action.get("_type").is_some() && self.flavor == Flavor::Elasticsearch8 {
+                return (400, json!({"error": "Action/metadata line [1] contains an unknown parameter [_type]", "status": 400}));
+            }
+            let name = match action["_index"].as_str().or(index) {
+                Some(name) => String::from(name),
+                None => return (400, json!({"error": "index is missing", "status": 400}))
+            };
+            if !self.indices.lock().unwrap().contains_key(&name) {
+                self.create(name.as_str(), json!({}));
+            }
+            let status = {
+                let mut statuses = self.item_statuses.lock().unwrap();
+                if statuses.is_empty() { 201 } else { statuses.remove(0) }
+            };
+            if status >= 300 {
+                let kind = if status == 429 { "es_rejected_execution_exception" } else { "mapper_parsing_exception" };
+                items.push(json!({"index": {"_index": name, "_id": action["_id"], "status": status, "error": {"type": kind, "reason": "mock"}}}));
+                continue;
+            }
+            self.documents.lock().unwrap().push((name.clone(), pair.get(1).cloned().unwrap_or_default()));
+            items.push(json!({"index": {"_index": name, "_id": action["_id"], "result": "created", "status": 201}}));
+        }
+        items.truncate(items.len().saturating_sub(*self.missing_items.lock().unwrap()));
+        let errors = items.iter().any(|item| item["index"].get("error").is_some());
+        (200, json!({"took": 1, "errors": errors, "items": items}))
+    }
+}
@@ -0,0 +1,143 @@
+use std::net::IpAddr;
+
+
+/// A network in CIDR notation, like `10.0.0.0/8` or `fd00::/8`
+/// A bare address is a network of one
+#[derive(Clone, Debug)]
+pub struct Cidr {
+    network : IpAddr,
+    prefix : u8
+}
+impl Cidr {
+    pub fn new(str : &str) -> Result<Self, String> {
+        let (address, prefix) = match str.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (str.trim(), None)
+        };
+        let network = address.parse::<IpAddr>().map_err(|_| format!("{} is not an ip address", address))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| format!("{} is not a valid prefix", prefix))?,
+            None => max
+        };
+        if prefix > max {
+            return Err(format!("/{} is too long for {}", prefix, network));
+        }
+
+        Ok(Cidr {
+            network,
+            prefix
+        })
+    }
+
+    pub fn contains(&self, ip : &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false
+        }
+    }
+}
+
+/// Proxies trusted when none are configured, only the machine itself
+pub fn default_trusted() -> Vec<Cidr> {
+    vec![
+        Cidr::new("127.0.0.0/8").unwrap(),
+        Cidr::new("::1/128").unwrap()
+    ]
+}
+
+/// Picks the client out of the proxy chain, ordered from the client towards nginx.
+///
+/// Anyone can send an X-Forwarded-For header, so only the entries added by our own proxies can be believed.
+/// Walking from the right, every trusted proxy is skipped, and the first address that isn't trusted is the client.
+/// If every address is trusted, the leftmost one is used.
+pub fn real_client(chain : &[IpAddr], trusted : &[Cidr]) -> Option<usize> {
+    if chain.is_empty() {
+        return None;
+    }
+    let client = chain.iter()
+        .rposition(|ip| !trusted.iter().any(|cidr| cidr.contains(ip)))
+        .unwrap_or(0);
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(str : &str) -> IpAddr {
+        str.parse().unwrap()
+    }
+
+    fn chain(addresses : &[&str]) -> Vec<IpAddr> {
+        addresses.iter().map(|address| ip(address)).collect()
+    }
+
+    #[test]
+    fn prefixes() {
+        let cases = [
+            ("0.0.0.0/0", "203.0.113.9", true),
+            ("0.0.0.0/0", "::1", false),
+            ("10.0.0.0/8", "10.255.0.1", true),
+            ("10.0.0.0/8", "11.0.0.1", false),
+            ("192.168.1.0/24", "192.168.1.255", true),
+            ("192.168.1.0/24", "192.168.2.1", false),
+            ("192.168.1.7/32", "192.168.1.7", true),
+            ("192.168.1.7/32", "192.168.1.8", false),
+            ("192.168.1.7", "192.168.1.8", false),
+            ("::/0", "2001:db8::1", true),
+            ("::/0", "127.0.0.1", false),
+            ("fd00::/8", "fd12:3456::1", true),
+            ("fd00::/8", "fe80::1", false),
+            ("::1/128", "::1", true),
+            ("::1/128", "::2", false)
+        ];
+        for (cidr, address, expected) in cases {
+            assert_eq!(Cidr::new(cidr).unwrap().contains(&ip(address)), expected, "{} contains {}", cidr, address);
+        }
+    }
+
+    #[test]
+    fn invalid_networks() {
+        assert!(Cidr::new("10.0.0.0/33").is_err());
+        assert!(Cidr::new("::/129").is_err());
+        assert!(Cidr::new("10.0.0.0/x").is_err());
+        assert!(Cidr::new("example.com").is_err());
+    }
+
+    #[test]
+    fn client_behind_trusted_proxies() {
+        let trusted = vec![Cidr::new("10.0.0.0/8").unwrap()];
+        let cases : [(&[&str], Option<usize>); 6] = [
+            // Only nginx's peer
+            (&["84.213.100.23"], Some(0)),
+            // Through the load balancer
+            (&["84.213.100.23", "10.0.0.5"], Some(0)),
+            // The client sent its own X-Forwarded-For, everything left of what our proxy saw is ignored
+            (&["1.1.1.1", "84.213.100.23", "10.0.0.5"], Some(1)),
+            // An untrusted proxy in front of ours could have written anything, so it's the client
+            (&["1.1.1.1", "84.213.100.23", "203.0.113.9", "10.0.0.5"], Some(2)),
+            // Nothing but our own proxies
+            (&["10.0.0.7", "10.0.0.5"], Some(0)),
+            (&[], None)
+        ];
+        for (addresses, expected) in cases {
+            assert_eq!(real_client(&chain(addresses), &trusted), expected, "{:?}", addresses);
+        }
+    }
+
+    #[test]
+    fn nothing_trusted() {
+        // Without trusted proxies the header can't be believed at all, nginx's peer is the client
+        assert_eq!(real_client(&chain(&["1.1.1.1", "84.213.100.23"]), &[]), Some(1));
+        // 127.0.0.1 is trusted by default
+        assert_eq!(real_client(&chain(&["84.213.100.23", "127.0.0.1"]), &default_trusted()), Some(0));
+    }
+}
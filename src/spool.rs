@@ -0,0 +1,286 @@
+use std::fs;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use colored::Colorize;
+use crate::logger::Logger;
+use crate::server::Server;
+
+
+/// Used when `spool_dir` isn't set in the config file
+pub const DEFAULT_SPOOL_DIR : &str = "/var/lib/rust-logger/spool";
+
+/// Used when `spool_max_mb` isn't set in the config file
+pub const DEFAULT_SPOOL_MAX_MB : u64 = 512;
+
+/// How long to wait before replaying again, doubled after every failed replay
+const MIN_BACKOFF : Duration = Duration::from_secs(5);
+const MAX_BACKOFF : Duration = Duration::from_secs(300);
+
+/// Bulks Elasticsearch didn't take, kept on disk until it's reachable again.
+///
+/// Each bulk is its own file, one document per line, named by a sequence number so they're replayed in the order they came in.
+/// When the spool grows past its limit the oldest bulks are dropped, so an outage can't fill the disk
+pub struct Spool {
+    dir: String,
+    max_bytes: u64,
+    /// Sequence number and size of every bulk on disk, oldest first
+    files: Vec<(u64, u64)>,
+    backoff: Duration,
+    next_replay: Instant
+}
+impl Spool {
+    /// Picks up whatever was left from the last run, it's replayed before anything new is sent
+    pub fn open(dir : &str, max_mb : u64) -> Self {
+        let mut files : Vec<(u64, u64)> = vec![];
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let seq = match name.strip_suffix(".ndjson").and_then(|seq| seq.parse::<u64>().ok()) {
+                    Some(seq) => seq,
+                    None => continue
+                };
+                files.push((seq, entry.metadata().map(|m| m.len()).unwrap_or(0)));
+            }
+        }
+        files.sort();
+        if !files.is_empty() {
+            println!("{} {} {}", "Found".yellow(), files.len().to_string().yellow(), "spooled bulks, they will be sent first".yellow());
+        }
+
+        Spool {
+            dir: String::from(dir),
+            max_bytes: max_mb * 1024 * 1024,
+            files,
+            backoff: MIN_BACKOFF,
+            next_replay: Instant::now()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// If there's something to replay, and it's been long enough since the last failed attempt
+    pub fn is_due(&self) -> bool {
+        !self.is_empty() && Instant::now() >= self.next_replay
+    }
+
+    fn file(&self, seq : u64) -> String {
+        format!("{}/{:020}.ndjson", self.dir, seq)
+    }
+
+    fn size(&self) -> u64 {
+        self.files.iter().map(|(_, size)| size).sum()
+    }
+
+    /// Written next to it first, so a crash halfway never leaves half a bulk to replay
+    fn write(&self, seq : u64, text : &str) -> Result<(), String> {
+        let path = self.file(seq);
+        let tmp = format!("{}.tmp", path);
+        let mut file = fs::File::create(tmp.as_str()).map_err(|e| format!("Unable to write {}: {}", tmp, e))?;
+        file.write_all(text.as_bytes()).and_then(|_| file.sync_all()).map_err(|e| format!("Unable to write {}: {}", tmp, e))?;
+        fs::rename(tmp.as_str(), path.as_str()).map_err(|e| format!("Unable to write {}: {}", path, e))
+    }
+
+    /// One document per line
+    fn text(log : &[Logger]) -> String {
+        let mut text = String::new();
+        for logger in log {
+            text.push_str(serde_json::to_string(logger).unwrap().as_str());
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Writes the bulk to disk. Once this returns Ok the documents are safe, and the checkpoint can be saved
+    pub fn push(&mut self, log : &[Logger]) -> Result<(), String> {
+        let text = Spool::text(log);
+        let size = text.len() as u64;
+
+        // Making room by dropping the oldest bulks, there's no way to get them back
+        while !self.files.is_empty() && self.size() + size > self.max_bytes {
+            let (seq, _) = self.files.remove(0);
+            let path = self.file(seq);
+            let lost = fs::read_to_string(path.as_str()).map(|text| text.lines().count()).unwrap_or(0);
+            println!("{} {} {}", "Spool is full, dropped".red(), lost.to_string().red(), "of the oldest documents".red());
+            fs::remove_file(path.as_str()).map_err(|e| format!("Unable to remove {}: {}", path, e))?;
+        }
+
+        fs::create_dir_all(self.dir.as_str()).map_err(|e| format!("Unable to create {}: {}", self.dir, e))?;
+        let seq = self.files.last().map(|(seq, _)| seq + 1).unwrap_or(0);
+        self.write(seq, text.as_str())?;
+
+        self.files.push((seq, size));
+        println!("{} {} {}", "Spooled".yellow(), log.len().to_string().yellow(), "documents until Elasticsearch is back".yellow());
+        Ok(())
+    }
+
+    /// Sends the spooled bulks oldest first, stopping at the first one that fails.
+    /// Returns true when the spool is empty afterwards
+    pub async fn replay(&mut self, server : &Server) -> bool {
+        if !self.is_due() {
+            return self.is_empty();
+        }
+        while let Some(&(seq, _)) = self.files.first() {
+            let path = self.file(seq);
+            // Kept until it can be read, it's only ever removed once it's been sent
+            let text = match fs::read(path.as_str()) {
+                Ok(text) => text,
+                Err(e) => {
+                    println!("{} {}: {}", "Unable to read".red(), path.red(), e);
+                    self.wait();
+                    return false;
+                }
+            };
+            let mut log : Vec<Logger> = vec![];
+            let mut bad : Vec<&[u8]> = vec![];
+            for line in text.split(|byte| *byte == b'\n').filter(|line| !line.is_empty()) {
+                match std::str::from_utf8(line).ok().and_then(|line| serde_json::from_str(line).ok()) {
+                    Some(logger) => log.push(logger),
+                    None => bad.push(line)
+                }
+            }
+            // Lines that can't be read back are set aside next to it, to be looked at by hand
+            if !bad.is_empty() {
+                let aside = format!("{}.bad", path);
+                if let Err(e) = fs::write(aside.as_str(), bad.join(&b'\n')) {
+                    println!("{} {}: {}", "Unable to write".red(), aside.red(), e);
+                    self.wait();
+                    return false;
+                }
+                println!("{} {} {}", bad.len().to_string().red(), "spooled lines couldn't be read, they were moved to".red(), aside.red());
+            }
+
+            let left = if log.is_empty() { vec![] } else { server.bulk(&log).await };
+            if !left.is_empty() {
+                // Only what's left is kept, what was taken or dead-lettered is done with
+                if left.len() < log.len() {
+                    let text = Spool::text(&left);
+                    match self.write(seq, text.as_str()) {
+                        Ok(()) => self.files[0].1 = text.len() as u64,
+                        Err(e) => println!("{}", e.red())
+                    }
+                }
+                println!("{}", "Elasticsearch is still unavailable".yellow());
+                self.wait();
+                return false;
+            }
+            if let Err(e) = fs::remove_file(path.as_str()) {
+                // It will be sent again on the next start, the documents keep their ids so nothing is indexed twice
+                println!("{} {}: {}", "Unable to remove".red(), path.red(), e);
+            }
+            self.files.remove(0);
+        }
+        println!("{}", "Caught up on the spool".green());
+        self.backoff = MIN_BACKOFF;
+        true
+    }
+
+    /// Puts off the next replay, a little longer every time
+    fn wait(&mut self) {
+        self.next_replay = Instant::now() + self.backoff;
+        println!("{} {}s", "Trying the spool again in".yellow(), self.backoff.as_secs().to_string().yellow());
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+    use std::path::PathBuf;
+    use crate::format::LogFormat;
+    use crate::mock::{Flavor, Mock};
+    use crate::server::is_es;
+
+    const COMBINED : &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+
+    fn block_on<F : std::future::Future>(future : F) -> F::Output {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn temp_dir(name : &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust-logger-spool-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn documents(count : usize) -> Vec<Logger> {
+        let format = LogFormat::new("combined", COMBINED).unwrap();
+        (1..=count)
+            .map(|i| Logger::new(format!(r#"10.0.0.{} - - [18/Oct/2022:08:41:00 +0000] "GET / HTTP/1.1" 200 12 "-" "curl/7.0""#, i), &format).unwrap())
+            .collect()
+    }
+
+    fn checked(mock : &Mock) -> Server {
+        let mut server = Server::new(format!("{}/logger", mock.url).as_str()).unwrap();
+        assert!(block_on(is_es(&mut server)));
+        server
+    }
+
+    #[test]
+    fn unreadable_bulks_are_kept() {
+        let mock = Mock::start(Flavor::Elasticsearch7, None);
+        let server = checked(&mock);
+        let dir = temp_dir("unreadable");
+        // Reading a directory fails like a file it has no permission for would
+        let path = dir.join(format!("{:020}.ndjson", 0));
+        fs::create_dir(&path).unwrap();
+
+        let mut spool = Spool::open(dir.to_str().unwrap(), 1);
+        assert!(!block_on(spool.replay(&server)));
+        assert!(path.exists());
+        assert!(!spool.is_empty());
+        assert!(!spool.is_due());
+    }
+
+    #[test]
+    fn lines_that_cant_be_read_are_set_aside() {
+        let mock = Mock::start(Flavor::Elasticsearch7, None);
+        let server = checked(&mock);
+        let dir = temp_dir("bad-lines");
+        let mut spool = Spool::open(dir.to_str().unwrap(), 1);
+        spool.push(&documents(2)).unwrap();
+        let path = spool.file(0);
+        let mut text = fs::read(path.as_str()).unwrap();
+        text.extend_from_slice(b"{\"truncated\n\xff\xfe\n");
+        fs::write(path.as_str(), text).unwrap();
+
+        assert!(block_on(spool.replay(&server)));
+        assert_eq!(mock.documents("logger").len(), 2);
+        assert!(!PathBuf::from(path.as_str()).exists());
+        assert_eq!(fs::read(format!("{}.bad", path)).unwrap(), b"{\"truncated\n\xff\xfe");
+    }
+
+    #[test]
+    fn only_whats_left_is_replayed() {
+        let mock = Mock::start(Flavor::Elasticsearch7, None);
+        let mut server = checked(&mock);
+        let dir = temp_dir("left");
+        let dead_letter = dir.join("dead-letter.ndjson");
+        server.set_dead_letter(dead_letter.to_str().unwrap());
+        let mut spool = Spool::open(dir.join("spool").to_str().unwrap(), 1);
+        let log = documents(3);
+        spool.push(&log).unwrap();
+
+        // One is taken, one can never be and one is busy
+        mock.answer_items(&[201, 400, 429]);
+        assert!(!block_on(spool.replay(&server)));
+        assert_eq!(fs::read_to_string(&dead_letter).unwrap().lines().count(), 1);
+        let left : Vec<Logger> = fs::read_to_string(spool.file(0)).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].get_id(), log[2].get_id());
+
+        spool.next_replay = Instant::now();
+        assert!(block_on(spool.replay(&server)));
+        assert!(spool.is_empty());
+        assert_eq!(mock.documents("logger").len(), 2);
+        assert_eq!(fs::read_to_string(&dead_letter).unwrap().lines().count(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use colored::Colorize;
+use serde_derive::{Deserialize, Serialize};
+
+
+/// Used when `state_file` isn't set in the config file
+pub const DEFAULT_STATE_FILE : &str = "/var/lib/rust-logger/state.json";
+
+/// How far into a log file Elasticsearch has confirmed.
+/// The inode tells if it's still the same file, or if it has been rotated since
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub inode: u64,
+    pub offset: u64,
+    /// Fingerprint of the start of the file, copytruncate keeps the inode so this tells if it was emptied since.
+    /// Missing in state files from before it was added
+    #[serde(default)]
+    pub head: Option<u64>
+}
+
+/// Checkpoints for every log file, kept on disk so a restart continues where it left off
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    #[serde(skip)]
+    path: String,
+    files: HashMap<String, Checkpoint>
+}
+impl State {
+    /// A missing or broken state file just means starting over, it's never fatal
+    pub fn load(path : &str) -> Self {
+        let mut state = match fs::read_to_string(path) {
+            Ok(text) => match serde_json::from_str::<State>(text.as_str()) {
+                Ok(state) => state,
+                Err(e) => {
+                    println!("{} {}: {}", "Ignoring invalid state file".yellow(), path.yellow(), e);
+                    State::default()
+                }
+            },
+            Err(_) => State::default()
+        };
+        state.path = String::from(path);
+        state
+    }
+
+    pub fn get(&self, file : &str) -> Option<Checkpoint> {
+        self.files.get(file).copied()
+    }
+
+    /// Writes to a temporary file first, so a crash halfway never leaves a broken state file
+    pub fn save(&mut self, checkpoints : &HashMap<String, Checkpoint>) -> Result<(), String> {
+        if checkpoints.iter().all(|(file, checkpoint)| self.get(file) == Some(*checkpoint)) {
+            return Ok(());
+        }
+        self.files.extend(checkpoints.iter().map(|(file, checkpoint)| (file.clone(), *checkpoint)));
+
+        if let Some(dir) = Path::new(self.path.as_str()).parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+        }
+        let tmp = format!("{}.tmp", self.path);
+        let text = serde_json::to_string_pretty(self).unwrap();
+        fs::write(tmp.as_str(), text).map_err(|e| format!("Unable to write {}: {}", tmp, e))?;
+        fs::rename(tmp.as_str(), self.path.as_str()).map_err(|e| format!("Unable to write {}: {}", self.path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+    use std::path::PathBuf;
+
+    fn temp_dir(name : &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust-logger-state-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn checkpoints(list : &[(&str, u64, u64)]) -> HashMap<String, Checkpoint> {
+        list.iter().map(|(file, inode, offset)| (String::from(*file), Checkpoint { inode: *inode, offset: *offset, head: None })).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        // The directory is made when it's first saved
+        let path = temp_dir("round-trip").join("lib").join("state.json");
+        let path = path.to_str().unwrap();
+        let mut state = State::load(path);
+        assert_eq!(state.get("/var/log/nginx/access.log"), None);
+
+        state.save(&checkpoints(&[("/var/log/nginx/access.log", 12, 3400)])).unwrap();
+        state.save(&checkpoints(&[("/var/log/nginx/other.log", 13, 10)])).unwrap();
+        assert!(!Path::new(format!("{}.tmp", path).as_str()).exists());
+
+        let state = State::load(path);
+        assert_eq!(state.get("/var/log/nginx/access.log"), Some(Checkpoint { inode: 12, offset: 3400, head: None }));
+        assert_eq!(state.get("/var/log/nginx/other.log"), Some(Checkpoint { inode: 13, offset: 10, head: None }));
+    }
+
+    #[test]
+    fn unchanged_isnt_written() {
+        let dir = temp_dir("unchanged");
+        let path = dir.join("state.json");
+        let mut state = State::load(path.to_str().unwrap());
+        state.save(&checkpoints(&[("access.log", 1, 10)])).unwrap();
+        fs::remove_file(&path).unwrap();
+        state.save(&checkpoints(&[("access.log", 1, 10)])).unwrap();
+        assert!(!path.exists());
+        state.save(&checkpoints(&[("access.log", 1, 20)])).unwrap();
+        assert_eq!(State::load(path.to_str().unwrap()).get("access.log").unwrap().offset, 20);
+    }
+
+    #[test]
+    fn broken_state_file_starts_over() {
+        let dir = temp_dir("broken");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        // Like a disk that filled up while writing it in place
+        fs::write(&path, r#"{"files": {"access.log": {"inode": 1, "off"#).unwrap();
+        let mut state = State::load(path.to_str().unwrap());
+        assert_eq!(state.get("access.log"), None);
+
+        state.save(&checkpoints(&[("access.log", 1, 10)])).unwrap();
+        assert_eq!(State::load(path.to_str().unwrap()).get("access.log"), Some(Checkpoint { inode: 1, offset: 10, head: None }));
+    }
+
+    #[test]
+    fn failed_save_keeps_the_old_state() {
+        let dir = temp_dir("failed");
+        let path = dir.join("state.json");
+        let mut state = State::load(path.to_str().unwrap());
+        state.save(&checkpoints(&[("access.log", 1, 10)])).unwrap();
+
+        // The temporary file can't be written, so the state file is never touched
+        fs::create_dir_all(dir.join("state.json.tmp")).unwrap();
+        assert!(state.save(&checkpoints(&[("access.log", 1, 20)])).is_err());
+        assert_eq!(State::load(path.to_str().unwrap()).get("access.log").unwrap().offset, 10);
+    }
+}
@@ -0,0 +1,371 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::net::{IpAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use colored::Colorize;
+use crate::tail::Line;
+
+
+/// The most a message can be, the biggest UDP datagram. TCP messages claiming or running to more are dropped with the connection
+const MAX_MESSAGE : usize = 65535;
+
+/// A syslog message with the header taken off
+#[derive(Debug, PartialEq, Eq)]
+pub struct Message {
+    /// The machine that sent it
+    pub hostname: Option<String>,
+    /// What nginx logged
+    pub text: String
+}
+
+/// Takes the header off a RFC 5424 or RFC 3164 message, like nginx sends with `access_log syslog:server=...`.
+/// Anything without a `<priority>` is kept as it is
+pub fn parse(raw : &str) -> Message {
+    let raw = raw.trim_end_matches(['\n', '\r', '\0']);
+    match strip_priority(raw) {
+        Some(rest) => match rest.strip_prefix("1 ") {
+            Some(rest) => rfc5424(rest),
+            None => rfc3164(rest)
+        },
+        None => Message {
+            hostname: None,
+            text: String::from(raw)
+        }
+    }
+}
+
+/// `<190>`, facility and severity, which isn't needed for anything
+fn strip_priority(raw : &str) -> Option<&str> {
+    let rest = raw.strip_prefix('<')?;
+    let end = rest.find('>')?;
+    if end == 0 || end > 3 || !rest[..end].chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(&rest[end + 1..])
+}
+
+fn hostname(host : &str) -> Option<String> {
+    if host.is_empty() || host == "-" {
+        return None;
+    }
+    Some(String::from(host))
+}
+
+/// `1 2022-10-18T08:41:00.003Z web1 nginx - - [structured data] message`, after the priority and version
+fn rfc5424(rest : &str) -> Message {
+    // Timestamp, hostname, app name, process id and message id, then the structured data and message
+    let mut fields = rest.splitn(6, ' ');
+    let host = fields.nth(1).unwrap_or("");
+    let rest = fields.nth(3).unwrap_or("");
+
+    let text = if let Some(text) = rest.strip_prefix('-') {
+        text
+    } else if rest.starts_with('[') {
+        // Elements like [id key="value"], where a quoted value can contain \] and spaces
+        let mut end = rest.len();
+        let mut quoted = false;
+        let mut escaped = false;
+        let mut in_element = false;
+        for (i, c) in rest.char_indices() {
+            if escaped {
+                escaped = false;
+                continue;
+            }
+            match c {
+                '\\' if quoted => escaped = true,
+                '"' if in_element => quoted = !quoted,
+                '[' if !quoted => in_element = true,
+                ']' if !quoted => in_element = false,
+                ' ' if !in_element => {
+                    end = i;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        &rest[end..]
+    } else {
+        rest
+    };
+
+    Message {
+        hostname: hostname(host),
+        text: String::from(text.strip_prefix(' ').unwrap_or(text).trim_start_matches('\u{feff}'))
+    }
+}
+
+/// `Oct 18 08:41:00 web1 nginx: message`, after the priority.
+/// The hostname is optional, and some senders use an ISO timestamp instead
+fn rfc3164(rest : &str) -> Message {
+    let bytes = rest.as_bytes();
+    // Only when the timestamp ends on a character boundary, 16 bytes in could be in the middle of one
+    let rest = match rest.get(16..) {
+        Some(after) if bytes[3] == b' ' && bytes[6] == b' ' && bytes[9] == b':' && bytes[12] == b':' => after,
+        _ => match rest.split_once(' ') {
+            Some((timestamp, rest)) if timestamp.starts_with(|c : char| c.is_ascii_digit()) && timestamp.contains('T') => rest,
+            _ => rest
+        }
+    };
+
+    // The tag ends with a colon, like "nginx:" or "nginx[123]:", whatever comes before it is the hostname
+    let (first, after_first) = rest.split_once(' ').unwrap_or((rest, ""));
+    if first.ends_with(':') {
+        return Message {
+            hostname: None,
+            text: String::from(after_first)
+        };
+    }
+    let text = match after_first.split_once(' ') {
+        Some((tag, text)) if tag.ends_with(':') => text,
+        _ => after_first
+    };
+    Message {
+        hostname: hostname(first),
+        text: String::from(text)
+    }
+}
+
+/// `peer` is the address it came from, which tells senders apart when they leave the hostname out
+fn send(raw : &str, source : usize, lines : &SyncSender<Line>, peer : Option<IpAddr>) -> bool {
+    let message = parse(raw);
+    if message.text.is_empty() {
+        return true;
+    }
+    let sender = message.hostname.clone().or_else(|| peer.map(|peer| peer.to_string()));
+    lines.send(Line {
+        source,
+        text: message.text,
+        checkpoint: None,
+        hostname: message.hostname,
+        sender
+    }).is_ok()
+}
+
+/// One message per datagram, which is how nginx sends them
+pub fn listen_udp(address : &str, source : usize, lines : SyncSender<Line>, stop : Arc<AtomicBool>) -> io::Result<JoinHandle<()>> {
+    let socket = UdpSocket::bind(address)?;
+    // So stop gets checked every now and then
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    println!("Listening for syslog on udp://{}", address);
+
+    Ok(thread::spawn(move || {
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        while !stop.load(Ordering::SeqCst) {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    println!("{} {}", "Unable to receive syslog:".red(), e);
+                    continue;
+                }
+            };
+            if !send(String::from_utf8_lossy(&buf[..len]).as_ref(), source, &lines, Some(peer.ip())) {
+                return;
+            }
+        }
+    }))
+}
+
+/// Accepts both newline separated messages, and octet counting (`123 <190>...`) from RFC 6587
+pub fn listen_tcp(address : &str, source : usize, lines : SyncSender<Line>, stop : Arc<AtomicBool>) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(address)?;
+    println!("Listening for syslog on tcp://{}", address);
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            match stream {
+                Ok(stream) => {
+                    let lines = lines.clone();
+                    let stop = Arc::clone(&stop);
+                    thread::spawn(move || read_tcp(stream, source, lines, stop));
+                }
+                Err(e) => println!("{} {}", "Unable to accept syslog connection:".red(), e)
+            }
+        }
+    }))
+}
+
+fn read_tcp(stream : TcpStream, source : usize, lines : SyncSender<Line>, stop : Arc<AtomicBool>) {
+    let address = stream.peer_addr().ok();
+    let peer = address.map(|peer| peer.to_string()).unwrap_or_default();
+    let mut reader = BufReader::new(stream);
+    while !stop.load(Ordering::SeqCst) {
+        let message = match read_message(&mut reader) {
+            Ok(message) => message,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                println!("{} {}, {}", "Dropping syslog connection from".red(), peer, e);
+                return;
+            }
+            Err(_) => return
+        };
+        if !send(String::from_utf8_lossy(&message).as_ref(), source, &lines, address.map(|address| address.ip())) {
+            return;
+        }
+    }
+}
+
+/// The next byte, without taking it
+fn peek<R : BufRead>(reader : &mut R) -> io::Result<Option<u8>> {
+    Ok(reader.fill_buf()?.first().copied())
+}
+
+/// Reads the next message, octet counted or up to the newline.
+/// It's only octet counted when the length and a space are followed by a `<priority>`, a raw line can start with a digit too.
+/// Anything longer than [MAX_MESSAGE] is invalid data, the peer can't be trusted with how much is buffered
+fn read_message<R : BufRead>(reader : &mut R) -> io::Result<Vec<u8>> {
+    if peek(reader)?.is_none() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    // Taken a byte at a time, so nothing past a newline is read when it turns out to be a line
+    let mut message = vec![];
+    while message.len() < MAX_MESSAGE {
+        match peek(reader)? {
+            Some(byte) if byte.is_ascii_digit() => {
+                message.push(byte);
+                reader.consume(1);
+            }
+            _ => break
+        }
+    }
+    if !message.is_empty() && peek(reader)? == Some(b' ') {
+        reader.consume(1);
+        if peek(reader)? == Some(b'<') {
+            let length : usize = match String::from_utf8_lossy(&message).parse() {
+                Ok(length) if length <= MAX_MESSAGE => length,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message length {} is too long", String::from_utf8_lossy(&message))))
+            };
+            message.resize(length, 0);
+            reader.read_exact(&mut message)?;
+            return Ok(message);
+        }
+        message.push(b' ');
+    }
+
+    reader.by_ref().take((MAX_MESSAGE + 1 - message.len()) as u64).read_until(b'\n', &mut message)?;
+    if message.len() > MAX_MESSAGE && !message.ends_with(b"\n") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no newline in {} bytes", MAX_MESSAGE)));
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE : &str = r#"10.0.0.1 - - [18/Oct/2022:08:41:00 +0000] "GET / HTTP/1.1" 200 12 "-" "curl/7.0""#;
+
+    fn message(hostname : Option<&str>, text : &str) -> Message {
+        Message {
+            hostname: hostname.map(String::from),
+            text: String::from(text)
+        }
+    }
+
+    #[test]
+    fn rfc3164() {
+        let cases = [
+            (format!("<190>Oct 18 08:41:00 web1 nginx: {}", LINE), message(Some("web1"), LINE)),
+            (format!("<190>Oct  8 08:41:00 web1 nginx[123]: {}\n", LINE), message(Some("web1"), LINE)),
+            // nginx leaves the hostname out unless it's told to
+            (format!("<190>Oct 18 08:41:00 nginx: {}", LINE), message(None, LINE)),
+            (format!("<190>2022-10-18T08:41:00+02:00 web1 nginx: {}", LINE), message(Some("web1"), LINE)),
+            (String::from("<13>Oct 18 08:41:00 - nginx: hi"), message(None, "hi"))
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(parse(raw.as_str()), expected, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn rfc3164_timestamp_on_a_character_boundary() {
+        // 16 bytes in is in the middle of the é, so it isn't taken as a timestamp
+        assert_eq!(parse("<13>Oct 18 08:41:00éx nginx: hi"), message(Some("Oct"), "18 08:41:00éx nginx: hi"));
+    }
+
+    #[test]
+    fn rfc5424() {
+        let cases = [
+            (format!("<190>1 2022-10-18T08:41:00.003Z web1 nginx - - - {}", LINE), message(Some("web1"), LINE)),
+            (format!("<190>1 2022-10-18T08:41:00.003Z - nginx 123 ID47 - {}", LINE), message(None, LINE)),
+            (format!(r#"<190>1 2022-10-18T08:41:00Z web1 nginx - - [origin ip="10.0.0.2" note="a \] b"][meta x="1"] {}"#, LINE), message(Some("web1"), LINE)),
+            (format!("<190>1 2022-10-18T08:41:00Z web1 nginx - - - \u{feff}{}", LINE), message(Some("web1"), LINE)),
+            (String::from("<190>1 2022-10-18T08:41:00Z web1 nginx - - -"), message(Some("web1"), ""))
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(parse(raw.as_str()), expected, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn without_priority() {
+        assert_eq!(parse(LINE), message(None, LINE));
+        assert_eq!(parse("<abc>text"), message(None, "<abc>text"));
+        assert_eq!(parse("<1234>text"), message(None, "<1234>text"));
+    }
+
+    fn read_all(mut input : &[u8]) -> (Vec<String>, Option<io::ErrorKind>) {
+        let mut messages = vec![];
+        loop {
+            match read_message(&mut input) {
+                Ok(message) => messages.push(String::from_utf8(message).unwrap()),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return (messages, None),
+                Err(e) => return (messages, Some(e.kind()))
+            }
+        }
+    }
+
+    #[test]
+    fn octet_counting() {
+        let (messages, error) = read_all(b"9 <13>hi\nyo5 <13>a<13>newline\n8 <13>last");
+        assert_eq!(messages, vec!["<13>hi\nyo", "<13>a", "<13>newline\n", "<13>last"]);
+        assert_eq!(error, None);
+    }
+
+    #[test]
+    fn octet_counting_is_capped() {
+        let (messages, error) = read_all(b"5 <13>a99999999999 <13>huge");
+        assert_eq!(messages, vec!["<13>a"]);
+        assert_eq!(error, Some(io::ErrorKind::InvalidData));
+
+        let (_, error) = read_all(format!("{} <13>", MAX_MESSAGE + 1).as_bytes());
+        assert_eq!(error, Some(io::ErrorKind::InvalidData));
+        let (messages, _) = read_all(format!("{} <13>{}", MAX_MESSAGE, "x".repeat(MAX_MESSAGE - 4)).as_bytes());
+        assert_eq!(messages[0].len(), MAX_MESSAGE);
+    }
+
+    #[test]
+    fn lines_starting_with_a_digit() {
+        let (messages, error) = read_all(format!("{}\n12 apples\n7\n5 <13>a", LINE).as_bytes());
+        assert_eq!(messages, vec![format!("{}\n", LINE).as_str(), "12 apples\n", "7\n", "<13>a"]);
+        assert_eq!(error, None);
+        assert_eq!(parse(messages[0].as_str()), message(None, LINE));
+    }
+
+    #[test]
+    fn newlines_are_capped() {
+        let (_, error) = read_all("x".repeat(MAX_MESSAGE + 1).as_bytes());
+        assert_eq!(error, Some(io::ErrorKind::InvalidData));
+        let (messages, error) = read_all(format!("<{}\n<13>next\n", "x".repeat(MAX_MESSAGE - 2)).as_bytes());
+        assert_eq!(messages.len(), 2);
+        assert_eq!(error, None);
+    }
+
+    #[test]
+    fn senders_are_told_apart() {
+        let (lines, received) = std::sync::mpsc::sync_channel(4);
+        let peer : IpAddr = "10.0.0.5".parse().unwrap();
+        assert!(send(format!("<190>Oct 18 08:41:00 web1 nginx: {}", LINE).as_str(), 0, &lines, Some(peer)));
+        assert!(send(format!("<190>Oct 18 08:41:00 nginx: {}", LINE).as_str(), 0, &lines, Some(peer)));
+        assert_eq!(received.recv().unwrap().sender.as_deref(), Some("web1"));
+        assert_eq!(received.recv().unwrap().sender.as_deref(), Some("10.0.0.5"));
+    }
+}
@@ -0,0 +1,706 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use colored::Colorize;
+use sha1::{Digest, Sha1};
+use crate::format::LogFormat;
+use crate::logger::detect_format;
+use crate::state::Checkpoint;
+
+
+/// A line read from one of the inputs, and where to resume from once it's indexed
+pub struct Line {
+    /// Which of the inputs it came from
+    pub source: usize,
+    pub text: String,
+    /// Only files have one, a pipe can't be read again
+    pub checkpoint: Option<Checkpoint>,
+    /// The machine that sent it, for syslog
+    pub hostname: Option<String>,
+    /// Who sent it over syslog, the hostname or else the address it came from
+    pub sender: Option<String>
+}
+
+/// Somewhere lines come from, and the format they're in
+pub struct Source {
+    /// Shown to the user
+    pub name: String,
+    /// The file or pipe the lines are read from, stored in source_file
+    pub path: Option<String>,
+    /// Not known up front for stdin, pipes and syslog, it's found from the first line that comes in
+    pub format: Option<LogFormat>,
+    /// Syslog senders don't have to agree on a format, so each one's is found from the first line it sends
+    pub senders: HashMap<String, LogFormat>
+}
+impl Source {
+    pub fn new(name : &str, path : Option<String>, format : Option<LogFormat>) -> Self {
+        Source {
+            name: String::from(name),
+            path,
+            format,
+            senders: HashMap::new()
+        }
+    }
+
+    /// The format `line` is in, detected from `formats` the first time this source, or its sender, is heard from
+    pub fn format_for(&mut self, line : &Line, formats : &[LogFormat]) -> Option<&LogFormat> {
+        if self.format.is_some() {
+            return self.format.as_ref();
+        }
+        let sender = match &line.sender {
+            Some(sender) => sender,
+            None => {
+                self.format = detect_format(line.text.as_str(), formats);
+                if let Some(detected) = self.format.as_ref() {
+                    println!("Reading {} as {}", self.name, detected.get_name());
+                }
+                return self.format.as_ref();
+            }
+        };
+        if !self.senders.contains_key(sender) {
+            if let Some(detected) = detect_format(line.text.as_str(), formats) {
+                println!("Reading {} from {} as {}", self.name, sender, detected.get_name());
+                self.senders.insert(sender.clone(), detected);
+            }
+        }
+        self.senders.get(sender)
+    }
+}
+
+/// How much of the start of a file is compared to tell if it was truncated and written again since
+const HEAD : u64 = 256;
+
+/// The two ways logrotate can rotate a file
+enum Rotation {
+    /// `create`, the file is moved away and nginx starts on a new one
+    Renamed(File),
+    /// `copytruncate`, the file is copied and then emptied in place
+    Truncated
+}
+
+/// Follows a log file like `tail -F`, keeping track of the byte offset of every complete line
+pub struct Tailer {
+    path: String,
+    inode: u64,
+    /// Offset right after the last complete line returned
+    pos: u64,
+    reader: BufReader<File>,
+    /// A line nginx hasn't finished writing yet
+    partial: Vec<u8>,
+    /// The first bytes up to pos, if they change the file was truncated even if it has grown past pos again
+    head: Vec<u8>,
+    /// The end was reached on the last call, so the file may have changed in any way since
+    idle: bool
+}
+impl Tailer {
+    /// Continues from the checkpoint if there is one, otherwise only new lines are read.
+    /// If the file was rotated while we weren't running, the rest of the rotated file is read first
+    pub fn open(path : &str, checkpoint : Option<Checkpoint>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+
+        let (file, inode, pos) = match checkpoint {
+            Some(checkpoint) if checkpoint.inode == metadata.ino() && checkpoint.offset <= metadata.len() && same_head(&file, checkpoint) => {
+                println!("Resuming {} from byte {}", path, checkpoint.offset);
+                (file, checkpoint.inode, checkpoint.offset)
+            }
+            Some(checkpoint) if checkpoint.inode == metadata.ino() => {
+                println!("{}", format!("{} has been truncated since the last run, reading it from the start", path).yellow());
+                (file, checkpoint.inode, 0)
+            }
+            Some(checkpoint) => match find_rotated(Path::new(path), checkpoint) {
+                Some(rotated) => {
+                    println!("{}", format!("{} has been rotated since the last run, finishing {} first", path, rotated.display()).yellow());
+                    (File::open(rotated)?, checkpoint.inode, checkpoint.offset)
+                }
+                None => {
+                    println!("{}", format!("{} has been rotated since the last run, reading it from the start", path).yellow());
+                    (file, metadata.ino(), 0)
+                }
+            },
+            None => (file, metadata.ino(), metadata.len())
+        };
+
+        Tailer::new(path, file, inode, pos)
+    }
+
+    /// A file that showed up while running, so everything in it is new
+    pub fn open_new(path : &str) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let inode = file.metadata()?.ino();
+        Tailer::new(path, file, inode, 0)
+    }
+
+    fn new(path : &str, file : File, inode : u64, pos : u64) -> io::Result<Self> {
+        let head = read_head(&file, HEAD.min(pos)).unwrap_or_default();
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(pos))?;
+        Ok(Tailer {
+            path: String::from(path),
+            inode,
+            pos,
+            reader,
+            partial: vec![],
+            head,
+            idle: false
+        })
+    }
+
+    /// Where to resume from if everything returned so far has been indexed
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            inode: self.inode,
+            offset: self.pos,
+            head: Some(fingerprint(&self.head))
+        }
+    }
+
+    /// The next complete line, or None if there's nothing new yet
+    pub fn next_line(&mut self) -> Option<String> {
+        // copytruncate followed by more than was read before, the size alone doesn't show it but the start is different
+        if self.idle {
+            self.idle = false;
+            if read_head(self.reader.get_ref(), self.head.len() as u64).as_ref() != Some(&self.head) {
+                self.start_over()?;
+            }
+        }
+
+        loop {
+            if let Some(line) = self.read_line() {
+                return Some(line);
+            }
+
+            let rotation = match self.rotation() {
+                Some(rotation) => rotation,
+                None => {
+                    self.idle = true;
+                    return None;
+                }
+            };
+            match rotation {
+                Rotation::Renamed(file) => {
+                    // nginx keeps writing to the old file until it has reopened its logs,
+                    // so anything that made it in since the last read comes first
+                    if let Some(line) = self.read_line() {
+                        return Some(line);
+                    }
+                    let unfinished = std::mem::take(&mut self.partial);
+
+                    let metadata = file.metadata().ok()?;
+                    println!("{}", format!("{} was rotated (renamed), continuing with the new file", self.path).yellow());
+                    self.reader = BufReader::new(file);
+                    self.inode = metadata.ino();
+                    self.pos = 0;
+                    self.head.clear();
+
+                    // Nothing more is coming to the old file, so a line without a newline is as done as it gets
+                    if !unfinished.is_empty() {
+                        return Some(to_line(&unfinished));
+                    }
+                }
+                Rotation::Truncated => self.start_over()?
+            }
+        }
+    }
+
+    /// After `copytruncate`
+    fn start_over(&mut self) -> Option<()> {
+        println!("{}", format!("{} was rotated (truncated), reading it from the start", self.path).yellow());
+        self.reader.seek(SeekFrom::Start(0)).ok()?;
+        self.pos = 0;
+        self.partial.clear();
+        self.head.clear();
+        Some(())
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        match self.reader.read_until(b'\n', &mut self.partial) {
+            Ok(_) if self.partial.ends_with(b"\n") => {
+                self.pos += self.partial.len() as u64;
+                let missing = (HEAD as usize).saturating_sub(self.head.len()).min(self.partial.len());
+                self.head.extend_from_slice(&self.partial[..missing]);
+                let line = to_line(&self.partial);
+                self.partial.clear();
+                Some(line)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                println!("Unable to read {}: {}", self.path, e);
+                None
+            }
+        }
+    }
+
+    /// Only called once the file has been read to the end
+    fn rotation(&self) -> Option<Rotation> {
+        // Shorter than what has already been read, it can only have been truncated
+        let current = self.reader.get_ref().metadata().ok()?;
+        if current.len() < self.pos + self.partial.len() as u64 {
+            return Some(Rotation::Truncated);
+        }
+
+        // Between logrotate moving the file and nginx creating a new one, there's nothing to open
+        let file = File::open(self.path.as_str()).ok()?;
+        let metadata = file.metadata().ok()?;
+        if metadata.ino() != self.inode {
+            return Some(Rotation::Renamed(file));
+        }
+        None
+    }
+}
+
+/// Reads the file in its own thread, until stop is set
+pub fn follow(mut tailer : Tailer, source : usize, lines : SyncSender<Line>, stop : Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            match tailer.next_line() {
+                Some(text) => {
+                    let line = Line {
+                        source,
+                        text,
+                        checkpoint: Some(tailer.checkpoint()),
+                        hostname: None,
+                        sender: None
+                    };
+                    // Nobody is listening anymore
+                    if lines.send(line).is_err() {
+                        break;
+                    }
+                }
+                None => thread::sleep(Duration::from_secs(1))
+            }
+        }
+    })
+}
+
+/// Reads stdin (`-`) or a named pipe in its own thread, until stop is set or stdin is closed
+pub fn stream(path : &str, source : usize, lines : SyncSender<Line>, stop : Arc<AtomicBool>) -> JoinHandle<()> {
+    let path = String::from(path);
+    thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            // Opening a named pipe waits until something starts writing to it
+            let reader : Box<dyn BufRead> = if path == "-" {
+                Box::new(io::stdin().lock())
+            } else {
+                match File::open(path.as_str()) {
+                    Ok(file) => Box::new(BufReader::new(file)),
+                    Err(e) => {
+                        println!("{} {}: {}", "Unable to open".red(), path.red(), e);
+                        return;
+                    }
+                }
+            };
+
+            for text in reader.split(b'\n') {
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                let text = match text {
+                    Ok(text) => to_line(&text),
+                    Err(e) => {
+                        println!("Unable to read {}: {}", path, e);
+                        break;
+                    }
+                };
+                let line = Line {
+                    source,
+                    text,
+                    checkpoint: None,
+                    hostname: None,
+                    sender: None
+                };
+                if lines.send(line).is_err() {
+                    return;
+                }
+            }
+
+            // stdin is done once it's closed, but a named pipe can get a new writer
+            if path == "-" {
+                return;
+            }
+        }
+    })
+}
+
+/// stdin or a named pipe, which can only be read once from wherever they are
+pub fn is_stream(location : &str) -> bool {
+    location == "-" || fs::metadata(location).map(|m| m.file_type().is_fifo()).unwrap_or(false)
+}
+
+pub fn is_glob(location : &str) -> bool {
+    location.contains(['*', '?', '['])
+}
+
+/// Glob patterns become every file they match, anything else is kept as is
+pub fn expand(locations : &[&str]) -> Vec<String> {
+    let mut paths : Vec<String> = vec![];
+    for location in locations {
+        if !is_glob(location) {
+            paths.push(String::from(*location));
+            continue;
+        }
+        match glob::glob(location) {
+            Ok(matches) => paths.extend(matches.filter_map(|path| path.ok()).map(|path| path.to_string_lossy().to_string())),
+            Err(e) => println!("{} {}: {}", "Invalid pattern".red(), location.red(), e)
+        }
+    }
+    paths
+}
+
+/// Files the glob patterns match now that aren't followed yet, like the log of a vhost added while running
+pub fn new_matches(patterns : &[&str], sources : &[Source]) -> Vec<String> {
+    expand(patterns).into_iter()
+        .filter(|path| !sources.iter().any(|source| source.path.as_ref() == Some(path)))
+        .collect()
+}
+
+fn to_line(bytes : &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_end_matches('\n')
+        .trim_end_matches('\r')
+        .to_string()
+}
+
+/// The first `len` bytes of the file, None if it's shorter
+fn read_head(file : &File, len : u64) -> Option<Vec<u8>> {
+    let mut head = vec![0; len as usize];
+    file.read_exact_at(&mut head, 0).ok()?;
+    Some(head)
+}
+
+/// Short enough to keep in the state file
+fn fingerprint(head : &[u8]) -> u64 {
+    let digest = Sha1::digest(head);
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Whether the file still starts like it did when the checkpoint was taken.
+/// Checkpoints from before fingerprints were kept can't tell, so they're trusted
+fn same_head(file : &File, checkpoint : Checkpoint) -> bool {
+    match checkpoint.head {
+        Some(head) => read_head(file, HEAD.min(checkpoint.offset)).is_some_and(|bytes| fingerprint(&bytes) == head),
+        None => true
+    }
+}
+
+/// Looks for the file the checkpoint was in, among the rotated ones next to it like access.log.1.
+/// Compressed files are skipped, they can't be continued from an offset
+fn find_rotated(path : &Path, checkpoint : Checkpoint) -> Option<PathBuf> {
+    let name = path.file_name()?.to_string_lossy().to_string();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    };
+
+    fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(name.as_str()))
+        .filter(|entry| !entry.file_name().to_string_lossy().ends_with(".gz"))
+        .find(|entry| entry.metadata().map(|m| m.ino() == checkpoint.inode && m.len() >= checkpoint.offset).unwrap_or(false))
+        .map(|entry| entry.path())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::process;
+
+    /// An empty directory of its own for every test
+    fn temp_dir(name : &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust-logger-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn append(path : &Path, text : &str) {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    fn lines(tailer : &mut Tailer) -> Vec<String> {
+        let mut lines = vec![];
+        while let Some(line) = tailer.next_line() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn only_new_lines_without_checkpoint() {
+        let dir = temp_dir("new-lines");
+        let log = dir.join("access.log");
+        append(&log, "old\n");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), None).unwrap();
+        assert!(lines(&mut tailer).is_empty());
+
+        append(&log, "first\nsecond\r\nunfin");
+        assert_eq!(lines(&mut tailer), vec!["first", "second"]);
+        append(&log, "ished\n");
+        assert_eq!(lines(&mut tailer), vec!["unfinished"]);
+    }
+
+    #[test]
+    fn rename_drains_the_old_file_first() {
+        let dir = temp_dir("rename");
+        let log = dir.join("access.log");
+        let rotated = dir.join("access.log.1");
+        append(&log, "");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), None).unwrap();
+        append(&log, "a\n");
+        assert_eq!(lines(&mut tailer), vec!["a"]);
+
+        // nginx still writes to the old file after the move, until it gets USR1
+        fs::rename(&log, &rotated).unwrap();
+        append(&rotated, "b\nc\n");
+        append(&log, "d\n");
+
+        assert_eq!(lines(&mut tailer), vec!["b", "c", "d"]);
+        let inode = fs::metadata(&log).unwrap().ino();
+        assert_eq!(tailer.checkpoint(), Checkpoint { inode, offset: 2, head: Some(fingerprint(b"d\n")) });
+    }
+
+    #[test]
+    fn rename_keeps_an_unfinished_last_line() {
+        let dir = temp_dir("rename-partial");
+        let log = dir.join("access.log");
+        append(&log, "");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), None).unwrap();
+        append(&log, "a\nb");
+        assert_eq!(lines(&mut tailer), vec!["a"]);
+
+        fs::rename(&log, dir.join("access.log.1")).unwrap();
+        append(&log, "c\n");
+        assert_eq!(lines(&mut tailer), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn copytruncate_starts_over() {
+        let dir = temp_dir("copytruncate");
+        let log = dir.join("access.log");
+        append(&log, "");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), None).unwrap();
+        append(&log, "first line\nsecond line\n");
+        assert_eq!(lines(&mut tailer), vec!["first line", "second line"]);
+
+        fs::copy(&log, dir.join("access.log.1")).unwrap();
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(0).unwrap();
+        append(&log, "x\n");
+
+        assert_eq!(lines(&mut tailer), vec!["x"]);
+        assert_eq!(tailer.checkpoint().offset, 2);
+    }
+
+    #[test]
+    fn copytruncate_and_grown_past_the_offset() {
+        let dir = temp_dir("copytruncate-grown");
+        let log = dir.join("access.log");
+        append(&log, "");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), None).unwrap();
+        append(&log, "a\n");
+        assert_eq!(lines(&mut tailer), vec!["a"]);
+
+        // Longer than before by the next poll, so the size alone doesn't show it
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(0).unwrap();
+        append(&log, "first line\nsecond line\n");
+
+        assert_eq!(lines(&mut tailer), vec!["first line", "second line"]);
+        assert_eq!(tailer.checkpoint().offset, 23);
+    }
+
+    #[test]
+    fn resumes_from_checkpoint() {
+        let dir = temp_dir("resume");
+        let log = dir.join("access.log");
+        append(&log, "");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), None).unwrap();
+        append(&log, "a\nb\n");
+        assert_eq!(tailer.next_line().as_deref(), Some("a"));
+        let checkpoint = tailer.checkpoint();
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), Some(checkpoint)).unwrap();
+        assert_eq!(lines(&mut tailer), vec!["b"]);
+    }
+
+    #[test]
+    fn resumes_in_file_rotated_while_stopped() {
+        let dir = temp_dir("resume-rotated");
+        let log = dir.join("access.log");
+        append(&log, "");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), None).unwrap();
+        append(&log, "a\nb\n");
+        assert_eq!(tailer.next_line().as_deref(), Some("a"));
+        let checkpoint = tailer.checkpoint();
+
+        fs::rename(&log, dir.join("access.log.1")).unwrap();
+        append(&log, "c\n");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), Some(checkpoint)).unwrap();
+        assert_eq!(lines(&mut tailer), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn truncated_while_stopped() {
+        let dir = temp_dir("resume-truncated");
+        let log = dir.join("access.log");
+        append(&log, "");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), None).unwrap();
+        append(&log, "first line\n");
+        assert_eq!(lines(&mut tailer), vec!["first line"]);
+        let checkpoint = tailer.checkpoint();
+
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(0).unwrap();
+        append(&log, "x\n");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), Some(checkpoint)).unwrap();
+        assert_eq!(lines(&mut tailer), vec!["x"]);
+    }
+
+    #[test]
+    fn truncated_and_grown_while_stopped() {
+        let dir = temp_dir("resume-truncated-grown");
+        let log = dir.join("access.log");
+        append(&log, "");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), None).unwrap();
+        append(&log, "a\n");
+        assert_eq!(lines(&mut tailer), vec!["a"]);
+        let checkpoint = tailer.checkpoint();
+
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(0).unwrap();
+        append(&log, "first line\n");
+
+        let mut tailer = Tailer::open(log.to_str().unwrap(), Some(checkpoint)).unwrap();
+        assert_eq!(lines(&mut tailer), vec!["first line"]);
+
+        // A checkpoint from an older state file has no fingerprint, it's trusted
+        let old = Checkpoint { head: None, ..tailer.checkpoint() };
+        append(&log, "b\n");
+        let mut tailer = Tailer::open(log.to_str().unwrap(), Some(old)).unwrap();
+        assert_eq!(lines(&mut tailer), vec!["b"]);
+    }
+
+    #[test]
+    fn files_added_while_running() {
+        let dir = temp_dir("glob");
+        let pattern = format!("{}/*.access.log", dir.display());
+        let first = dir.join("a.access.log");
+        append(&first, "old\n");
+        let sources = vec![Source::new("a", Some(first.to_string_lossy().to_string()), None)];
+        assert!(new_matches(&[pattern.as_str()], &sources).is_empty());
+
+        let second = dir.join("b.access.log");
+        append(&second, "first\n");
+        append(&dir.join("b.error.log"), "");
+        assert_eq!(new_matches(&[pattern.as_str()], &sources), vec![second.to_string_lossy().to_string()]);
+
+        // Nothing in it has been read yet
+        let mut tailer = Tailer::open_new(second.to_str().unwrap()).unwrap();
+        append(&second, "second\n");
+        assert_eq!(lines(&mut tailer), vec!["first", "second"]);
+    }
+
+    fn line(text : &str, sender : Option<&str>) -> Line {
+        Line {
+            source: 0,
+            text: String::from(text),
+            checkpoint: None,
+            hostname: None,
+            sender: sender.map(String::from)
+        }
+    }
+
+    #[test]
+    fn each_syslog_sender_has_its_own_format() {
+        let formats = LogFormat::presets();
+        let main = r#"84.213.100.23 - - [18/Oct/2022:08:41:00 +0000] "GET / HTTP/1.1" 200 12 "-" "curl/7.0" "10.0.0.1""#;
+        let combined = r#"84.213.100.23 - - [18/Oct/2022:08:41:00 +0000] "GET / HTTP/1.1" 200 12 "-" "curl/7.0""#;
+        let mut source = Source::new("syslog udp://0.0.0.0:514", None, None);
+
+        assert_eq!(source.format_for(&line(main, Some("web1")), &formats).map(LogFormat::get_name).as_deref(), Some("main"));
+        assert_eq!(source.format_for(&line(combined, Some("10.0.0.2")), &formats).map(LogFormat::get_name).as_deref(), Some("combined"));
+        // A combined line from web1 is still read as main, it just won't match
+        assert_eq!(source.format_for(&line(combined, Some("web1")), &formats).map(LogFormat::get_name).as_deref(), Some("main"));
+        // Nothing is kept for a sender that sent something unreadable first
+        assert!(source.format_for(&line("garbage", Some("web2")), &formats).is_none());
+        assert_eq!(source.format_for(&line(combined, Some("web2")), &formats).map(LogFormat::get_name).as_deref(), Some("combined"));
+        assert!(source.format.is_none());
+
+        // Everything else has one format for all of it
+        let mut source = Source::new("stdin", Some(String::from("-")), None);
+        assert_eq!(source.format_for(&line(main, None), &formats).map(LogFormat::get_name).as_deref(), Some("main"));
+        assert_eq!(source.format_for(&line(combined, None), &formats).map(LogFormat::get_name).as_deref(), Some("main"));
+    }
+
+    #[test]
+    fn named_pipe() {
+        let dir = temp_dir("fifo");
+        let fifo = dir.join("access.pipe");
+        assert!(process::Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+        let path = fifo.to_str().unwrap();
+        assert!(is_stream(path));
+        assert!(is_stream("-"));
+        append(&dir.join("access.log"), "");
+        assert!(!is_stream(dir.join("access.log").to_str().unwrap()));
+
+        let (sender, lines) = std::sync::mpsc::sync_channel::<Line>(16);
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = stream(path, 3, sender, Arc::clone(&stop));
+        let main = r#"84.213.100.23 - - [18/Oct/2022:08:41:00 +0000] "GET / HTTP/1.1" 200 12 "-" "curl/7.0" "10.0.0.1""#;
+        let receive = || lines.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Opening it waits for the reader on the other end
+        let mut writer = OpenOptions::new().write(true).open(&fifo).unwrap();
+        writer.write_all(format!("{}\r\n{}\n", main, main).as_bytes()).unwrap();
+        drop(writer);
+        let first = receive();
+        assert_eq!((first.source, first.text.as_str()), (3, main));
+        assert!(first.checkpoint.is_none());
+        let mut source = Source::new(path, Some(String::from(path)), None);
+        assert_eq!(source.format_for(&first, &LogFormat::presets()).map(LogFormat::get_name).as_deref(), Some("main"));
+        assert_eq!(receive().text, main);
+
+        // Once the writer is gone, it waits for the next one
+        let mut writer = OpenOptions::new().write(true).open(&fifo).unwrap();
+        writer.write_all(b"again\n").unwrap();
+        drop(writer);
+        assert_eq!(receive().text, "again");
+
+        // And stops when told, once it's no longer waiting for a writer. It might not be anymore, so that one can be left waiting
+        stop.store(true, Ordering::SeqCst);
+        let late = fifo.clone();
+        thread::spawn(move || {
+            let mut writer = OpenOptions::new().write(true).open(&late).unwrap();
+            let _ = writer.write_all(b"after stop\n");
+        });
+        for _ in 0..50 {
+            if handle.is_finished() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(handle.is_finished());
+        assert!(lines.try_recv().is_err());
+    }
+}
@@ -0,0 +1,437 @@
+use colored::Colorize;
+use serde_json::{json, Value};
+use sha1::{Sha1, Digest};
+use crate::config::Config;
+use crate::logger::Logger;
+use crate::server::Server;
+use crate::version::Distribution;
+
+
+/// The index template, and the lifecycle policy that goes with it, rust-logger keeps up to date on the server.
+/// New indices get their mapping, settings and alias from the template, so nothing has to be put on them one by one
+pub struct Template {
+    shards: Option<u32>,
+    replicas: Option<u32>,
+    /// Like `30s`, how soon documents can be searched. Longer is cheaper
+    refresh_interval: Option<String>,
+    /// Days after an index is created before it's made read only and merged down
+    warm_days: Option<u32>,
+    /// Days after an index is created before it's deleted
+    delete_days: Option<u32>
+}
+impl Template {
+    /// None when `index_template` is turned off
+    pub fn from_config(config : &Config) -> Result<Option<Self>, String> {
+        if config.index_template == Some(false) {
+            return Ok(None);
+        }
+        // With a single index the policy would apply to all of it, deleting every document at once
+        if (config.ilm_warm_days.is_some() || config.ilm_delete_days.is_some()) && config.index_pattern.is_none() {
+            return Err(String::from("ilm_warm_days and ilm_delete_days need an index_pattern"));
+        }
+        if let (Some(warm), Some(delete)) = (config.ilm_warm_days, config.ilm_delete_days) {
+            if warm >= delete {
+                return Err(String::from("ilm_warm_days has to be less than ilm_delete_days"));
+            }
+        }
+        Ok(Some(Template {
+            shards: config.index_shards,
+            replicas: config.index_replicas,
+            refresh_interval: config.refresh_interval.clone(),
+            warm_days: config.ilm_warm_days,
+            delete_days: config.ilm_delete_days
+        }))
+    }
+
+    /// Named after the db, so several loggers can share a cluster
+    fn name(server : &Server) -> String {
+        format!("rust-logger-{}", server.get_db())
+    }
+
+    fn has_policy(&self) -> bool {
+        self.warm_days.is_some() || self.delete_days.is_some()
+    }
+
+    /// An ILM policy for Elasticsearch. Indices aren't rolled over, so the ages count from when an index was created
+    fn ilm_policy(&self) -> Value {
+        let mut phases = json!({
+            "hot": {
+                "min_age": "0ms",
+                "actions": { "set_priority": { "priority": 100 } }
+            }
+        });
+        if let Some(days) = self.warm_days {
+            phases["warm"] = json!({
+                "min_age": format!("{}d", days),
+                "actions": {
+                    "set_priority": { "priority": 50 },
+                    "readonly": {},
+                    "forcemerge": { "max_num_segments": 1 }
+                }
+            });
+        }
+        if let Some(days) = self.delete_days {
+            phases["delete"] = json!({
+                "min_age": format!("{}d", days),
+                "actions": { "delete": {} }
+            });
+        }
+        json!({ "policy": { "phases": phases } })
+    }
+
+    /// OpenSearch has ISM instead, with states that move on by age, and attaches itself to new indices with `ism_template`
+    fn ism_policy(&self, server : &Server) -> Value {
+        let mut states = vec![];
+        let mut state = |name : &str, actions : Value, next : Option<(&str, u32)>| {
+            let transitions = match next {
+                Some((to, days)) => json!([{ "state_name": to, "conditions": { "min_index_age": format!("{}d", days) } }]),
+                None => json!([])
+            };
+            states.push(json!({ "name": name, "actions": actions, "transitions": transitions }));
+        };
+
+        let after_hot = match (self.warm_days, self.delete_days) {
+            (Some(days), _) => Some(("warm", days)),
+            (None, Some(days)) => Some(("delete", days)),
+            (None, None) => None
+        };
+        state("hot", json!([]), after_hot);
+        if self.warm_days.is_some() {
+            state("warm", json!([{ "read_only": {} }, { "force_merge": { "max_num_segments": 1 } }]), self.delete_days.map(|days| ("delete", days)));
+        }
+        if self.delete_days.is_some() {
+            state("delete", json!([{ "delete": {} }]), None);
+        }
+
+        json!({
+            "policy": {
+                "description": format!("Managed by rust-logger for {}", server.get_db()),
+                "default_state": "hot",
+                "states": states,
+                "ism_template": [{ "index_patterns": server.index_patterns(), "priority": 200 }]
+            }
+        })
+    }
+
+    fn policy(&self, server : &Server, distribution : Distribution) -> Option<Value> {
+        if !self.has_policy() {
+            return None;
+        }
+        match distribution {
+            Distribution::Elasticsearch => Some(self.ilm_policy()),
+            Distribution::OpenSearch => Some(self.ism_policy(server))
+        }
+    }
+
+    /// Where the policy lives, both are named like the template
+    fn policy_url(server : &Server, distribution : Distribution) -> String {
+        match distribution {
+            Distribution::Elasticsearch => format!("{}/_ilm/policy/{}", server.get_host(), Template::name(server)),
+            Distribution::OpenSearch => format!("{}/_plugins/_ism/policies/{}", server.get_host(), Template::name(server))
+        }
+    }
+
+    /// The composable index template. The alias is only added for time based indices, a single index has the name itself
+    fn index_template(&self, server : &Server, distribution : Distribution) -> Value {
+        let mut settings = json!({});
+        if let Some(shards) = self.shards {
+            settings["number_of_shards"] = json!(shards);
+        }
+        if let Some(replicas) = self.replicas {
+            settings["number_of_replicas"] = json!(replicas);
+        }
+        if let Some(refresh_interval) = &self.refresh_interval {
+            settings["refresh_interval"] = json!(refresh_interval);
+        }
+        // ISM picks up its indices through ism_template instead
+        if self.has_policy() && distribution == Distribution::Elasticsearch {
+            settings["index.lifecycle.name"] = json!(Template::name(server));
+        }
+
+        let mut template = json!({
+            "settings": settings,
+            "mappings": Logger::mapping()["mappings"]
+        });
+        if server.is_time_based() {
+            template["aliases"] = json!({ server.get_db(): {} });
+        }
+        json!({
+            "index_patterns": server.index_patterns(),
+            // Above the built in templates of Elasticsearch 8, like logs-*-*
+            "priority": 200,
+            "template": template
+        })
+    }
+
+    /// Lines that can't be parsed go to `<db>-unparsed`, which a time based pattern like `logger-*` matches as well.
+    /// This one has a higher priority, so that index gets neither the mapping, the alias nor the lifecycle policy
+    fn unparsed_template(&self, server : &Server, distribution : Distribution) -> Option<Value> {
+        if !server.is_time_based() {
+            return None;
+        }
+        let settings = match distribution {
+            Distribution::Elasticsearch => json!({}),
+            // ism_template would still pick it up
+            Distribution::OpenSearch => json!({ "index.plugins.index_state_management.auto_manage": false })
+        };
+        Some(json!({
+            "index_patterns": [server.unparsed_index()],
+            "priority": 201,
+            "template": { "settings": settings }
+        }))
+    }
+
+    /// Checks the template and policy on the server, and puts them there if they're missing or out of date.
+    /// Returns false when the server can't have index templates, then the mapping is put on each index instead
+    pub async fn install(&self, server : &Server) -> Result<bool, String> {
+        let version = match server.get_version() {
+            Some(version) => version.clone(),
+            None => return Ok(false)
+        };
+        if !version.has_index_templates() {
+            println!("{} {}", version.to_string().yellow(), "doesn't have composable index templates, the mapping is put on each index instead".yellow());
+            return Ok(false);
+        }
+        let name = Template::name(server);
+        let policy = self.policy(server, version.distribution);
+        let mut template = self.index_template(server, version.distribution);
+        let mut unparsed = self.unparsed_template(server, version.distribution);
+
+        // Ours once it has the hash of what it should be, compared instead of the template itself, which the server reformats
+        let mut hasher = Sha1::new();
+        hasher.update(json!([policy, template, unparsed]).to_string().into_bytes());
+        let hash = format!("{:X}", hasher.finalize());
+        template["_meta"] = json!({ "managed_by": "rust-logger", "hash": hash });
+        if let Some(unparsed) = &mut unparsed {
+            unparsed["_meta"] = json!({ "managed_by": "rust-logger" });
+        }
+
+        let client = server.http();
+        let template_url = format!("{}/_index_template/{}", server.get_host(), name);
+        let current : Value = match client.get(template_url.as_str()).send().await {
+            Ok(response) if response.status().is_success() => response.json().await.unwrap_or_default(),
+            Ok(_) => Value::Null,
+            Err(e) => return Err(format!("Unable to get index template {}: {}", name, e))
+        };
+        let installed = current["index_templates"][0]["index_template"]["_meta"]["hash"].as_str();
+
+        // Someone might have removed the policy, while the template still points to it
+        let policy_url = Template::policy_url(server, version.distribution);
+        let current_policy : Value = match &policy {
+            Some(_) => match client.get(policy_url.as_str()).send().await {
+                Ok(response) if response.status().is_success() => response.json().await.unwrap_or_default(),
+                _ => Value::Null
+            },
+            None => Value::Null
+        };
+        let unparsed_url = format!("{}-unparsed", template_url);
+        let has_unparsed = match &unparsed {
+            Some(_) => matches!(client.get(unparsed_url.as_str()).send().await, Ok(response) if response.status().is_success()),
+            None => true
+        };
+        if installed == Some(hash.as_str()) && (policy.is_none() || !current_policy.is_null()) && has_unparsed {
+            return Ok(true);
+        }
+
+        if let Some(policy) = &policy {
+            let mut request = client.put(policy_url.as_str());
+            // ISM only updates a policy when told which version of it is being replaced
+            if version.distribution == Distribution::OpenSearch && !current_policy.is_null() {
+                request = request.query(&[("if_seq_no", current_policy["_seq_no"].to_string()), ("if_primary_term", current_policy["_primary_term"].to_string())]);
+            }
+            let response = request
+                .json(policy)
+                .headers(server.headers(false))
+                .send()
+                .await
+                .map_err(|e| format!("Unable to put lifecycle policy {}: {}", name, e))?;
+            if !response.status().is_success() {
+                return Err(format!("Lifecycle policy {} was rejected: {}", name, response.text().await.unwrap_or_default()));
+            }
+        }
+
+        let response = client.put(template_url.as_str())
+            .json(&template)
+            .headers(server.headers(false))
+            .send()
+            .await
+            .map_err(|e| format!("Unable to put index template {}: {}", name, e))?;
+        if !response.status().is_success() {
+            return Err(format!("Index template {} was rejected: {}", name, response.text().await.unwrap_or_default()));
+        }
+        if let Some(unparsed) = &unparsed {
+            let response = client.put(unparsed_url.as_str())
+                .json(unparsed)
+                .headers(server.headers(false))
+                .send()
+                .await
+                .map_err(|e| format!("Unable to put index template {}-unparsed: {}", name, e))?;
+            if !response.status().is_success() {
+                return Err(format!("Index template {}-unparsed was rejected: {}", name, response.text().await.unwrap_or_default()));
+            }
+        }
+        match installed {
+            Some(_) => println!("Updated index template {}, it's used for indices created from now on", name),
+            None => println!("Installed index template {}", name)
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::LogFormat;
+    use crate::mock::{Flavor, Mock};
+    use crate::server::is_es;
+    use crate::version::Version;
+
+    fn block_on<F : std::future::Future>(future : F) -> F::Output {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// Time based, checked like at startup
+    fn server(mock : &Mock) -> Server {
+        let mut server = Server::new(format!("{}/logger", mock.url).as_str()).unwrap();
+        assert!(block_on(is_es(&mut server)));
+        server.set_index_pattern("logger-%Y.%m.%d").unwrap();
+        server
+    }
+
+    fn pattern() -> Option<String> {
+        Some(String::from("logger-%Y.%m.%d"))
+    }
+
+    fn template(config : Config) -> Template {
+        Template::from_config(&config).unwrap().unwrap()
+    }
+
+    fn puts(mock : &Mock, path : &str) -> usize {
+        mock.requests().iter().filter(|request| request.method == "PUT" && request.path.starts_with(path)).count()
+    }
+
+    #[test]
+    fn new_indices_get_the_template() {
+        let mock = Mock::start(Flavor::Elasticsearch8, None);
+        let mut server = server(&mock);
+        let template = template(Config { index_pattern: pattern(), index_replicas: Some(0), refresh_interval: Some(String::from("30s")), ilm_warm_days: Some(7), ilm_delete_days: Some(30), ..Config::default() });
+        assert_eq!(block_on(template.install(&server)), Ok(true));
+
+        let installed = mock.template("rust-logger-logger").unwrap();
+        assert_eq!(installed["index_patterns"], json!(["logger-*"]));
+        assert_eq!(installed["template"]["settings"], json!({"number_of_replicas": 0, "refresh_interval": "30s", "index.lifecycle.name": "rust-logger-logger"}));
+        assert!(installed["template"]["aliases"]["logger"].is_object());
+        let policy = mock.policy("rust-logger-logger").unwrap();
+        assert_eq!(policy["policy"]["phases"]["warm"]["min_age"], "7d");
+        assert_eq!(policy["policy"]["phases"]["delete"]["min_age"], "30d");
+
+        server.set_template(true);
+        let format = LogFormat::new("combined", r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#).unwrap();
+        let log = vec![Logger::new(String::from(r#"10.0.0.1 - - [18/Oct/2022:08:41:00 +0000] "GET / HTTP/1.1" 200 12 "-" "curl/7.0""#), &format).unwrap()];
+        assert!(block_on(server.bulk(&log)).is_empty());
+
+        // Created empty, the template fills it in
+        assert_eq!(mock.last("/logger-2022.10.18").unwrap().body, "{}");
+        let index = mock.index("logger-2022.10.18").unwrap();
+        assert!(index["mappings"]["properties"]["time"].is_object());
+        assert!(index["aliases"]["logger"].is_object());
+        assert_eq!(index["settings"]["index.lifecycle.name"], "rust-logger-logger");
+    }
+
+    #[test]
+    fn only_updated_when_changed() {
+        let mock = Mock::start(Flavor::Elasticsearch7, None);
+        let server = server(&mock);
+        let config = || Config { index_pattern: pattern(), ilm_delete_days: Some(30), ..Config::default() };
+        assert_eq!(block_on(template(config()).install(&server)), Ok(true));
+        assert_eq!(block_on(template(config()).install(&server)), Ok(true));
+        // The template and the one for unparsed lines
+        assert_eq!(puts(&mock, "/_index_template/"), 2);
+        assert_eq!(puts(&mock, "/_ilm/policy/"), 1);
+
+        assert_eq!(block_on(template(Config { index_shards: Some(2), ..config() }).install(&server)), Ok(true));
+        assert_eq!(puts(&mock, "/_index_template/"), 4);
+        assert_eq!(mock.template("rust-logger-logger").unwrap()["template"]["settings"]["number_of_shards"], 2);
+    }
+
+    #[test]
+    fn opensearch_gets_an_ism_policy() {
+        let mock = Mock::start(Flavor::OpenSearch2, None);
+        let server = server(&mock);
+        assert_eq!(block_on(template(Config { index_pattern: pattern(), ilm_delete_days: Some(30), ..Config::default() }).install(&server)), Ok(true));
+
+        let policy = mock.policy("rust-logger-logger").unwrap();
+        assert_eq!(policy["policy"]["ism_template"][0]["index_patterns"], json!(["logger-*"]));
+        assert_eq!(policy["policy"]["states"][0]["transitions"][0], json!({"state_name": "delete", "conditions": {"min_index_age": "30d"}}));
+        assert_eq!(policy["policy"]["states"][1]["actions"], json!([{"delete": {}}]));
+        assert!(mock.template("rust-logger-logger").unwrap()["template"]["settings"].get("index.lifecycle.name").is_none());
+
+        // Replacing it has to name the version being replaced
+        assert_eq!(block_on(template(Config { index_pattern: pattern(), ilm_warm_days: Some(7), ilm_delete_days: Some(60), ..Config::default() }).install(&server)), Ok(true));
+        let states = &mock.policy("rust-logger-logger").unwrap()["policy"]["states"];
+        assert_eq!(states.as_array().unwrap().len(), 3);
+        assert_eq!(states[1]["transitions"][0]["conditions"]["min_index_age"], "60d");
+    }
+
+    #[test]
+    fn unparsed_lines_are_left_alone() {
+        for flavor in [Flavor::Elasticsearch8, Flavor::OpenSearch2] {
+            let mock = Mock::start(flavor, None);
+            let mut server = server(&mock);
+            assert_eq!(block_on(template(Config { index_pattern: pattern(), ilm_delete_days: Some(30), ..Config::default() }).install(&server)), Ok(true));
+            server.set_template(true);
+            assert!(block_on(server.bulk_unparsed(&[json!({"line": "unknown - - [18/Oct/2022:08:41:00 +0000]"})])));
+
+            let index = mock.index("logger-unparsed").unwrap();
+            assert!(index.get("aliases").is_none(), "{:?}", flavor);
+            assert!(index.get("mappings").is_none(), "{:?}", flavor);
+            assert!(index["settings"].get("index.lifecycle.name").is_none(), "{:?}", flavor);
+            let auto_manage = if flavor == Flavor::OpenSearch2 { json!(false) } else { Value::Null };
+            assert_eq!(index["settings"]["index.plugins.index_state_management.auto_manage"], auto_manage, "{:?}", flavor);
+
+            // Someone removed it
+            mock.remove_template("rust-logger-logger-unparsed");
+            assert_eq!(block_on(template(Config { index_pattern: pattern(), ilm_delete_days: Some(30), ..Config::default() }).install(&server)), Ok(true));
+            assert!(mock.template("rust-logger-logger-unparsed").is_some());
+        }
+    }
+
+    #[test]
+    fn single_index_has_no_alias() {
+        let mock = Mock::start(Flavor::Elasticsearch8, None);
+        let mut server = Server::new(format!("{}/logger", mock.url).as_str()).unwrap();
+        assert!(block_on(is_es(&mut server)));
+        assert_eq!(block_on(template(Config::default()).install(&server)), Ok(true));
+
+        let installed = mock.template("rust-logger-logger").unwrap();
+        assert_eq!(installed["index_patterns"], json!(["logger"]));
+        assert!(installed["template"].get("aliases").is_none());
+        assert!(mock.policy("rust-logger-logger").is_none());
+    }
+
+    #[test]
+    fn needs_elasticsearch_7_8() {
+        let mock = Mock::start(Flavor::Elasticsearch7, None);
+        let mut server = server(&mock);
+        server.set_version(Version::from_root(&json!({"cluster_name": "old", "version": {"number": "7.7.1"}})).unwrap());
+        assert_eq!(block_on(template(Config::default()).install(&server)), Ok(false));
+        assert_eq!(puts(&mock, "/"), 0);
+    }
+
+    #[test]
+    fn warm_before_delete() {
+        assert!(Template::from_config(&Config { index_pattern: pattern(), ilm_warm_days: Some(30), ilm_delete_days: Some(30), ..Config::default() }).is_err());
+        assert!(Template::from_config(&Config { index_template: Some(false), ..Config::default() }).unwrap().is_none());
+    }
+
+    #[test]
+    fn lifecycle_needs_time_based_indices() {
+        assert!(Template::from_config(&Config { ilm_delete_days: Some(30), ..Config::default() }).is_err());
+        assert!(Template::from_config(&Config { ilm_warm_days: Some(7), ..Config::default() }).is_err());
+        assert!(Template::from_config(&Config { index_pattern: pattern(), ilm_delete_days: Some(30), ..Config::default() }).unwrap().is_some());
+    }
+}
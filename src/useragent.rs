@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use regex::{Captures, Regex};
+use serde_derive::{Deserialize, Serialize};
+
+
+/// The regex database, built into the binary so it works without anything on disk.
+/// Same idea as uap-core, the first regex that matches wins
+const REGEXES : &str = include_str!("useragent.json");
+
+/// How many distinct user agents to remember before starting over
+const CACHE_SIZE : usize = 10000;
+
+/// What the user agent says about the client
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UserAgent {
+    pub browser: Option<String>,
+    pub browser_version: Option<String>,
+    pub os: Option<String>,
+    pub device: Option<String>,
+    pub is_bot: bool
+}
+
+/// A single entry in the database, replacements can use $1, $2 etc. from the regex
+#[derive(Deserialize)]
+struct Rule {
+    regex: String,
+    family_replacement: Option<String>,
+    version_replacement: Option<String>,
+    os_replacement: Option<String>,
+    device_replacement: Option<String>
+}
+
+#[derive(Deserialize)]
+struct Database {
+    bot_parsers: Vec<Rule>,
+    user_agent_parsers: Vec<Rule>,
+    os_parsers: Vec<Rule>,
+    device_parsers: Vec<Rule>
+}
+
+struct Parser {
+    regex: Regex,
+    rule: Rule
+}
+impl Parser {
+    fn compile(rules : Vec<Rule>) -> Vec<Parser> {
+        rules.into_iter()
+            .map(|rule| Parser {
+                regex: Regex::new(rule.regex.as_str()).expect("Invalid regex in useragent.json"),
+                rule
+            })
+            .collect()
+    }
+}
+
+/// Fills in $1, $2 etc. with the groups from the match.
+/// Without a replacement, `default` is used, which is also just a group like "$1"
+fn replace(replacement : &Option<String>, default : &str, cap : &Captures) -> Option<String> {
+    let mut out = String::new();
+    cap.expand(replacement.as_deref().unwrap_or(default), &mut out);
+    let out = String::from(out.trim());
+    if out.is_empty() {
+        return None;
+    }
+    Some(out)
+}
+
+/// Classifies user agents, remembering the ones it has already seen
+pub struct UserAgentParser {
+    bots: Vec<Parser>,
+    browsers: Vec<Parser>,
+    os: Vec<Parser>,
+    devices: Vec<Parser>,
+    cache: HashMap<String, UserAgent>
+}
+impl UserAgentParser {
+    pub fn new() -> Self {
+        let database : Database = serde_json::from_str(REGEXES).expect("Invalid useragent.json");
+        UserAgentParser {
+            bots: Parser::compile(database.bot_parsers),
+            browsers: Parser::compile(database.user_agent_parsers),
+            os: Parser::compile(database.os_parsers),
+            devices: Parser::compile(database.device_parsers),
+            cache: HashMap::new()
+        }
+    }
+
+    pub fn parse(&mut self, user_agent : &str) -> UserAgent {
+        if let Some(ua) = self.cache.get(user_agent) {
+            return ua.clone();
+        }
+
+        let ua = self.classify(user_agent);
+        // There's no end to the amount of made up user agents, so don't let the cache grow forever
+        if self.cache.len() >= CACHE_SIZE {
+            self.cache.clear();
+        }
+        self.cache.insert(String::from(user_agent), ua.clone());
+        ua
+    }
+
+    fn classify(&self, user_agent : &str) -> UserAgent {
+        let mut ua = UserAgent::default();
+
+        let bot = self.bots.iter()
+            .find_map(|p| p.regex.captures(user_agent).map(|cap| replace(&p.rule.family_replacement, "$1", &cap)));
+        ua.is_bot = bot.is_some();
+
+        for p in &self.browsers {
+            if let Some(cap) = p.regex.captures(user_agent) {
+                ua.browser = replace(&p.rule.family_replacement, "$1", &cap);
+                ua.browser_version = replace(&p.rule.version_replacement, "$2", &cap);
+                break;
+            }
+        }
+        if ua.browser.is_none() {
+            ua.browser = bot.flatten();
+        }
+
+        ua.os = self.os.iter()
+            .find_map(|p| p.regex.captures(user_agent).map(|cap| replace(&p.rule.os_replacement, "$1", &cap)))
+            .flatten();
+
+        ua.device = if ua.is_bot {
+            Some(String::from("Spider"))
+        } else {
+            self.devices.iter()
+                .find_map(|p| p.regex.captures(user_agent).map(|cap| replace(&p.rule.device_replacement, "$1", &cap)))
+                .flatten()
+        };
+        ua
+    }
+}
+impl Default for UserAgentParser {
+    fn default() -> Self {
+        UserAgentParser::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The user agent, then browser and version, OS, device and whether it's a bot
+    type Case = (&'static str, Option<&'static str>, Option<&'static str>, Option<&'static str>, Option<&'static str>, bool);
+
+    fn cases() -> Vec<Case> {
+        vec![
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0.0.0 Safari/537.36",
+                Some("Chrome"), Some("118.0"), Some("Windows 10"), Some("Desktop"), false),
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0.0.0 Safari/537.36 Edg/118.0.2088.76",
+                Some("Edge"), Some("118.0"), Some("Windows 10"), Some("Desktop"), false),
+            ("Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:109.0) Gecko/20100101 Firefox/119.0",
+                Some("Firefox"), Some("119.0"), Some("Mac OS X"), Some("Mac"), false),
+            ("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Safari/605.1.15",
+                Some("Safari"), Some("17.1"), Some("Mac OS X"), Some("Mac"), false),
+            ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Mobile/15E148 Safari/604.1",
+                Some("Mobile Safari"), Some("17.1"), Some("iOS"), Some("iPhone"), false),
+            ("Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/119.0.0.0 Mobile Safari/537.36",
+                Some("Chrome Mobile"), Some("119.0"), Some("Android"), Some("Pixel 8"), false),
+            ("Mozilla/5.0 (Linux; Android 13; SM-S918B) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/23.0 Chrome/115.0.0.0 Mobile Safari/537.36",
+                Some("Samsung Internet"), Some("23.0"), Some("Android"), Some("Samsung SM-S918B"), false),
+            ("Mozilla/5.0 (Linux; Android 12; CUBOT KINGKONG 7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Mobile Safari/537.36",
+                Some("Chrome Mobile"), Some("117.0"), Some("Android"), Some("CUBOT KINGKONG 7"), false),
+            ("Mozilla/5.0 (Windows NT 6.1; Trident/7.0; rv:11.0) like Gecko",
+                Some("IE"), Some("11.0"), Some("Windows 7"), Some("Desktop"), false),
+            ("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+                Some("Googlebot"), Some("2.1"), None, Some("Spider"), true),
+            ("Mozilla/5.0 (compatible; SeekportBot; +https://bot.seekport.com)",
+                Some("Other bot"), None, None, Some("Spider"), true),
+            ("Mozilla/5.0 (compatible; Some crawler; +https://example.com)",
+                Some("Other bot"), None, None, Some("Spider"), true),
+            ("curl/7.88.1",
+                Some("curl"), Some("7.88"), None, Some("Spider"), true),
+            ("-",
+                None, None, None, None, false)
+        ]
+    }
+
+    #[test]
+    fn classifies() {
+        let parser = UserAgentParser::new();
+        for (user_agent, browser, version, os, device, is_bot) in cases() {
+            let ua = parser.classify(user_agent);
+            assert_eq!(ua.browser.as_deref(), browser, "{}", user_agent);
+            assert_eq!(ua.browser_version.as_deref(), version, "{}", user_agent);
+            assert_eq!(ua.os.as_deref(), os, "{}", user_agent);
+            assert_eq!(ua.device.as_deref(), device, "{}", user_agent);
+            assert_eq!(ua.is_bot, is_bot, "{}", user_agent);
+        }
+    }
+
+    #[test]
+    fn cache_is_bounded() {
+        let mut parser = UserAgentParser::new();
+        let first = parser.parse("curl/7.88.1");
+        assert_eq!(parser.cache.len(), 1);
+        // The same answer from the cache
+        assert_eq!(parser.parse("curl/7.88.1").browser, first.browser);
+        assert_eq!(parser.cache.len(), 1);
+
+        for i in 1..CACHE_SIZE {
+            parser.parse(format!("curl/{}", i).as_str());
+        }
+        assert_eq!(parser.cache.len(), CACHE_SIZE);
+        // Starts over instead of growing
+        assert_eq!(parser.parse("Wget/1.21").browser.as_deref(), Some("Wget"));
+        assert_eq!(parser.cache.len(), 1);
+    }
+}
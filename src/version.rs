@@ -0,0 +1,83 @@
+use std::fmt;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use serde_json::Value;
+
+
+/// What the server runs, both answer `GET /` the same way apart from the version
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Distribution {
+    Elasticsearch,
+    OpenSearch
+}
+
+/// The version of a server, read from its answer to `GET /`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    pub distribution: Distribution,
+    /// Like `8.11.1`
+    pub number: String,
+    pub major: u32
+}
+impl Version {
+    pub fn from_root(root : &Value) -> Result<Self, String> {
+        let number = match root["version"]["number"].as_str() {
+            Some(number) => String::from(number),
+            None => return Err(String::from("This does not look like an Elasticsearch DB"))
+        };
+        let major = number.split('.').next()
+            .and_then(|major| major.parse::<u32>().ok())
+            .ok_or(format!("Unknown version {}", number))?;
+
+        // OpenSearch can be set to claim it's Elasticsearch 7.10.2, then only the tagline gives it away
+        let distribution = if root["version"]["distribution"].as_str() == Some("opensearch") || root["tagline"].as_str().map(|tagline| tagline.contains("OpenSearch")).unwrap_or(false) {
+            Distribution::OpenSearch
+        } else if root["tagline"].is_string() || root["cluster_name"].is_string() {
+            Distribution::Elasticsearch
+        } else {
+            return Err(String::from("This does not look like an Elasticsearch DB"));
+        };
+
+        Ok(Version { distribution, number, major })
+    }
+
+    /// Elasticsearch 6 and older need a mapping type in every request, which isn't done
+    pub fn check(&self) -> Result<(), String> {
+        if self.distribution == Distribution::Elasticsearch && self.major < 7 {
+            return Err(format!("{} isn't supported, it needs 7 or newer", self));
+        }
+        Ok(())
+    }
+
+    /// Composable index templates (`_index_template`) came in Elasticsearch 7.8, OpenSearch always had them
+    pub fn has_index_templates(&self) -> bool {
+        let minor = self.number.split('.').nth(1).and_then(|minor| minor.parse::<u32>().ok()).unwrap_or(0);
+        self.distribution == Distribution::OpenSearch || self.major > 7 || minor >= 8
+    }
+
+    /// Requests are written for Elasticsearch 7, so newer versions are asked to answer like the one before them.
+    /// OpenSearch rejects these, it gets plain JSON
+    pub fn headers(&self, ndjson : bool) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if self.distribution != Distribution::Elasticsearch || self.major < 8 {
+            return headers;
+        }
+        let compatible = self.major - 1;
+        let json = format!("application/vnd.elasticsearch+json; compatible-with={}", compatible);
+        let body = match ndjson {
+            true => format!("application/vnd.elasticsearch+x-ndjson; compatible-with={}", compatible),
+            false => json.clone()
+        };
+        headers.insert(ACCEPT, HeaderValue::from_str(json.as_str()).unwrap());
+        headers.insert(CONTENT_TYPE, HeaderValue::from_str(body.as_str()).unwrap());
+        headers
+    }
+}
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.distribution {
+            Distribution::Elasticsearch => "Elasticsearch",
+            Distribution::OpenSearch => "OpenSearch"
+        };
+        write!(f, "{} {}", name, self.number)
+    }
+}